@@ -6,8 +6,6 @@ import util
 
 flight = json.load(sys.stdin)
 
-outbound_steps = flight["setup"]["outbound_steps"]
-inbound_steps = flight["setup"]["inbound_steps"]
 path = util.reconstruct_path(flight)
 
 fig = plt.figure(figsize = (8, 5))
@@ -15,8 +13,9 @@ left, right = fig.subfigures(1, 2)
 
 ax = left.subplots(1, 1)
 ax.set_title("Path")
-ax.plot(path[:outbound_steps+1,0], path[:outbound_steps+1,1], label="outbound")
-ax.plot(path[outbound_steps+1:,0], path[outbound_steps+1:,1], label="inbound")
+for phase in flight["phases"]:
+    steps = util.phase_steps(flight, phase["name"])
+    ax.plot(path[steps,0], path[steps,1], label=phase["name"])
 ax.plot([0], [0], "*", label="nest")
 ax.legend()
 ax.set_xlabel("x (steps)")
@@ -25,7 +24,7 @@ ax.set_ylabel("y (steps)")
 ax.set(adjustable='datalim', aspect='equal')
 
 upper, lower = right.subplots(2, 1, sharex=True)
-memory = np.array(flight["memory_record"])
+memory = util.memory_record(flight)
 for i in range(util.n_mem):
     upper.plot(memory[:,i])
 #upper.set_ylim(0, 1)
@@ -2,9 +2,36 @@ import numpy as np
 
 def reconstruct_path(flight):
     physical_states = np.array(flight["physical_states"])
-    path = [[0,0]] + np.cumsum(physical_states[:,0:2], axis=0)
+    path = np.concatenate([[[0,0]], physical_states[:,3:5]])
     return path
 
+def air_and_ground_velocity(flight):
+    """Velocity relative to the air and over the ground at each state."""
+    physical_states = np.array(flight["physical_states"])
+    air = physical_states[:,0:2]
+    wind = physical_states[:,5:7] if physical_states.shape[1] > 5 else np.zeros_like(air)
+    return air, air + wind
+
+def altitude(flight):
+    """Altitude at each state, zero for flights recorded without one."""
+    physical_states = np.array(flight["physical_states"])
+    return physical_states[:,7] if physical_states.shape[1] > 7 else np.zeros(len(physical_states))
+
+def phase_steps(flight, name):
+    """Indices into the path of the states produced by the named phase."""
+    step_phases = np.array(flight["step_phases"])
+    names = [phase["name"] for phase in flight["phases"]]
+    return np.nonzero(step_phases == names.index(name))[0] + 1
+
+def memory_record(flight):
+    """Memory record as an array, with NaN for steps where it was not recorded."""
+    return np.array([m if m is not None else [np.nan] * n_mem for m in flight["memory_record"]])
+
+def probe(flight, population):
+    """Path indices and activities of a probed population."""
+    record = flight["probe_record"]
+    return np.array(record["steps"]) + 1, np.array(record["activities"][population])
+
 
 nature_single = 50 #89.0 / 25.4
 figsize = (nature_single, nature_single)
@@ -0,0 +1,145 @@
+//! Reproducible parallel batches of trials.
+//!
+//! Every (parameter point, trial) pair gets its own seed derived from a single master seed,
+//! so the results do not depend on how rayon schedules the work, and any single flight
+//! can be replayed from its recorded seed.
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    experiment::Experiment,
+    stats::{Aggregate, Bootstrap, FlightStats},
+    util::derive_seed,
+    FlightData,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrialRecord {
+    pub point: usize,
+    pub trial: usize,
+    pub seed: u64,
+    pub stats: FlightStats,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub master_seed: u64,
+    pub points: Vec<Experiment>,
+    /// Ordered by point, then by trial.
+    pub trials: Vec<TrialRecord>,
+    /// Summary of each point's trials.
+    #[serde(default)]
+    pub aggregates: Vec<Aggregate>,
+}
+
+impl BatchResult {
+    pub fn point_trials(&self, point: usize) -> impl Iterator<Item = &TrialRecord> {
+        self.trials
+            .iter()
+            .filter(move |record| record.point == point)
+    }
+
+    /// Summarizes the trials of a point, with bootstrap resampling seeded from the master seed.
+    pub fn aggregate(&self, point: usize, bootstrap: &Bootstrap) -> Aggregate {
+        Aggregate::new(
+            self.point_trials(point).map(|record| &record.stats),
+            bootstrap,
+            derive_seed(self.master_seed, &[point as u64]),
+        )
+    }
+
+    /// Re-runs a single recorded trial, giving the full flight data.
+    pub fn replay(&self, record: &TrialRecord) -> FlightData {
+        self.points[record.point].with_seed(record.seed).run()
+    }
+}
+
+pub fn trial_seed(master_seed: u64, point: usize, trial: usize) -> u64 {
+    derive_seed(master_seed, &[point as u64, trial as u64])
+}
+
+/// Runs `trials` trials of every experiment in parallel. The experiments' own seeds are ignored.
+pub fn run_batch(points: Vec<Experiment>, trials: usize, master_seed: u64) -> BatchResult {
+    let records = (0..points.len() * trials)
+        .into_par_iter()
+        .map(|index| {
+            let (point, trial) = (index / trials, index % trials);
+            let seed = trial_seed(master_seed, point, trial);
+            let experiment = points[point].with_seed(seed);
+            let result = experiment.run();
+            TrialRecord {
+                point,
+                trial,
+                seed,
+                stats: FlightStats::analyze(&result),
+            }
+        })
+        .collect();
+
+    let mut batch = BatchResult {
+        master_seed,
+        points,
+        trials: records,
+        aggregates: Vec::new(),
+    };
+    batch.aggregates = (0..batch.points.len())
+        .map(|point| batch.aggregate(point, &Bootstrap::default()))
+        .collect();
+    batch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+        [setup]
+        outbound_steps = 300
+        inbound_steps = 300
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = true
+
+        [model]
+        variant = "logistic"
+        h = 0.0048329304
+        w0 = 6.1584935e-5
+        beta = 0.3
+    "#;
+
+    fn points() -> Vec<Experiment> {
+        [SPEC.to_owned(), SPEC.replace("beta = 0.3", "beta = 0.6")]
+            .iter()
+            .map(|spec| Experiment::from_toml(spec).unwrap())
+            .collect()
+    }
+
+    fn json(value: &impl Serialize) -> String {
+        serde_json::to_string(value).unwrap()
+    }
+
+    fn run_on_threads(threads: usize) -> BatchResult {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| run_batch(points(), 3, 42))
+    }
+
+    #[test]
+    fn results_do_not_depend_on_threads() {
+        assert_eq!(json(&run_on_threads(1)), json(&run_on_threads(4)));
+    }
+
+    #[test]
+    fn trials_replay_from_their_seeds() {
+        let batch = run_on_threads(2);
+        for record in &batch.trials {
+            let flight = batch.replay(record);
+            assert_eq!(json(&FlightStats::analyze(&flight)), json(&record.stats));
+            assert_eq!(json(&flight), json(&batch.replay(record)));
+        }
+    }
+}
@@ -0,0 +1,961 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use nalgebra::Vector2;
+use ndarray::Array;
+use stone_model::{
+    batch::run_batch,
+    decode::{HeadingDecoding, HomeVectorDecoding},
+    experiment::{Experiment, Noise, Variant},
+    foraging::{Foraging, ForagingPolicy, Recalibration, TripRoute},
+    model::{Compass, OpticFlow, Senses, VentralFlow},
+    movement::{Altitude, Capture, Displacement, TimeStep, Wind},
+    outbound::{LevyFlight, Outbound},
+    probe::{Population, Probes},
+    protocol::{Control, SearchOnset, StopConditions},
+    sink::{CsvSink, JsonLinesSink},
+    sky::{Compensation, NoiseLevel, Skylight, SolarDay, Sun},
+    stats::{Bootstrap, FlightStats},
+    track::Track,
+    vision::{Landmark, Learning, MemoryKind, Vision},
+    world::{Collision, Obstacle, World},
+    FlightData, Setup, COMMON_SEED,
+};
+
+#[derive(Parser)]
+#[command(
+    name = "stone-model",
+    about = "Simulate path integration in the insect central complex"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Simulate a single homing trial and write the flight data as JSON
+    Run(RunArgs),
+    /// Send one agent on repeated foraging trips and write per-trip statistics as JSON
+    Forage(ForageArgs),
+    /// Grid search over the dynamics parameters of a weight-based variant, or repeated trials of
+    /// the reference variant
+    Sweep(SweepArgs),
+    /// Measure how many flights per second can be simulated
+    Bench(BenchArgs),
+    /// Compute statistics for previously recorded flight data
+    Analyze(AnalyzeArgs),
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum VariantName {
+    Reference,
+    Affine,
+    Logistic,
+    LogisticAmp,
+}
+
+#[derive(Args)]
+struct ModelArgs {
+    #[arg(long, value_enum, default_value = "logistic")]
+    variant: VariantName,
+    #[arg(long, default_value_t = 0.0048329304)]
+    h: f32,
+    #[arg(long, default_value_t = 6.1584935e-5)]
+    w0: f32,
+    #[arg(long, default_value_t = 0.6631579)]
+    beta: f32,
+    /// Override the variant's default turn sharpness
+    #[arg(long, allow_hyphen_values = true)]
+    turn_sharpness: Option<f32>,
+}
+
+impl ModelArgs {
+    fn variant(&self) -> Variant {
+        let ModelArgs {
+            h,
+            w0,
+            beta,
+            turn_sharpness,
+            ..
+        } = *self;
+        match self.variant {
+            VariantName::Reference => Variant::Reference { turn_sharpness },
+            VariantName::Affine => Variant::Affine {
+                beta,
+                turn_sharpness,
+            },
+            VariantName::Logistic => Variant::Logistic {
+                h,
+                w0,
+                beta,
+                turn_sharpness,
+            },
+            VariantName::LogisticAmp => Variant::LogisticAmp {
+                h,
+                w0,
+                beta,
+                turn_sharpness,
+            },
+        }
+    }
+}
+
+#[derive(Args)]
+struct SetupArgs {
+    #[arg(long, default_value_t = 1500)]
+    outbound_steps: usize,
+    #[arg(long, default_value_t = 1500)]
+    inbound_steps: usize,
+    #[arg(long, default_value_t = 0.15)]
+    acceleration_out: f32,
+    #[arg(long, default_value_t = 0.1)]
+    acceleration_in: f32,
+    /// Keep a constant outbound speed instead of varying it
+    #[arg(long)]
+    constant_speed: bool,
+    /// Do not record the memory state at every step
+    #[arg(long)]
+    no_memory: bool,
+    /// Move the agent by X Y before homing
+    #[arg(long, num_args = 2, value_names = ["X", "Y"], allow_hyphen_values = true)]
+    translate: Option<Vec<f32>>,
+    /// Release the agent at X Y before homing
+    #[arg(long, num_args = 2, value_names = ["X", "Y"], allow_hyphen_values = true, conflicts_with = "translate")]
+    release_at: Option<Vec<f32>>,
+    /// Rotate the agent by this many radians before homing
+    #[arg(long, allow_hyphen_values = true)]
+    rotate: Option<f32>,
+    /// Where to capture the agent for displacement
+    #[arg(long, value_enum, default_value = "feeder")]
+    capture_at: CaptureName,
+    /// Stop homing within this distance of the nest, or travel to a feeder within it of the feeder
+    #[arg(long)]
+    stop_radius: Option<f32>,
+    /// Stop homing once the decoded home vector has shrunk to this fraction of its length
+    /// at the start of homing
+    #[arg(long)]
+    stop_memory: Option<f32>,
+    /// Stop homing once the path over the last WINDOW steps is no straighter than STRAIGHTNESS
+    #[arg(long, num_args = 2, value_names = ["WINDOW", "STRAIGHTNESS"])]
+    stop_search: Option<Vec<String>>,
+    /// Record the activity of these populations, e.g. tb1,cpu1a,motor
+    #[arg(long, value_delimiter = ',')]
+    probe: Vec<Population>,
+    /// Record probed activities every N steps
+    #[arg(long, default_value_t = 1)]
+    probe_every: usize,
+    /// Constant wind velocity X Y
+    #[arg(long, num_args = 2, value_names = ["X", "Y"], allow_hyphen_values = true)]
+    wind: Option<Vec<f32>>,
+    /// Gusts of up to STRENGTH on top of the wind, changing every PERIOD time units
+    #[arg(long, num_args = 2, value_names = ["STRENGTH", "PERIOD"])]
+    gusts: Option<Vec<f32>>,
+    /// Outbound route generator
+    #[arg(long, value_enum, default_value = "random-walk")]
+    route: RouteName,
+    /// Concentration of von Mises turns
+    #[arg(long, default_value_t = 100.0)]
+    kappa: f32,
+    /// Step of the turn in an L-shaped route
+    #[arg(long, default_value_t = 750)]
+    turn_at: usize,
+    /// Angle of the turn in an L-shaped route
+    #[arg(long, default_value_t = std::f32::consts::FRAC_PI_2, allow_hyphen_values = true)]
+    turn_angle: f32,
+    /// Power-law exponent of Lévy segment lengths
+    #[arg(long, default_value_t = 2.0)]
+    levy_mu: f32,
+    /// Shortest Lévy segment, in time units
+    #[arg(long, default_value_t = 10.0)]
+    levy_min_length: f32,
+    /// Points X Y ... of a waypoint route
+    #[arg(long, num_args = 2.., value_names = ["X", "Y"], allow_negative_numbers = true)]
+    waypoints: Vec<f32>,
+    /// Simulated time per step
+    #[arg(long, default_value_t = 1.0)]
+    dt: f32,
+    /// Physics updates per network update
+    #[arg(long, default_value_t = 1)]
+    substeps: usize,
+    /// Outbound flight altitude
+    #[arg(long)]
+    outbound_altitude: Option<f32>,
+    /// Let the outbound altitude undulate by AMPLITUDE every PERIOD time units
+    #[arg(long, num_args = 2, value_names = ["AMPLITUDE", "PERIOD"], requires = "outbound_altitude")]
+    altitude_undulation: Option<Vec<f32>>,
+    /// Homing flight altitude; by default the agent keeps the outbound altitude
+    #[arg(long)]
+    inbound_altitude: Option<f32>,
+    /// Sense ventral optic flow, matching planar flow at this altitude
+    #[arg(long)]
+    ventral_flow: Option<f32>,
+    /// Read the heading from skylight polarization, with the sun fixed at AZIMUTH and ELEVATION
+    #[arg(long, num_args = 2, value_names = ["AZIMUTH", "ELEVATION"], allow_negative_numbers = true)]
+    skylight: Option<Vec<f32>>,
+    /// Read the heading from skylight polarization, with the sun moving along its path
+    /// at LATITUDE from HOUR of the day
+    #[arg(long, num_args = 2, value_names = ["LATITUDE", "HOUR"], allow_negative_numbers = true, conflicts_with = "skylight")]
+    solar_day: Option<Vec<f32>>,
+    /// Declination of the sun on the solar day
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    declination: f32,
+    /// Hours of the solar day per unit of simulation time
+    #[arg(long, default_value_t = 1.0 / 3600.0)]
+    hours_per_time: f32,
+    /// How the agent allows for the sun moving
+    #[arg(long, value_enum, default_value = "none")]
+    sun_compensation: CompensationName,
+    /// Clockwise turn of the sun per hour that linear compensation assumes, in radians
+    #[arg(long, default_value_t = std::f32::consts::PI / 12.0, allow_negative_numbers = true)]
+    compensation_rate: f32,
+    /// Standard deviation of the noise in the degree of polarization the sky compass senses
+    #[arg(long, default_value = "0")]
+    polarization_noise: NoiseLevel,
+    /// Chance that each of the sky compass's views of the sky is behind cloud
+    #[arg(long, default_value_t = 0.0)]
+    cloud_cover: f32,
+    /// Keep the agent within the rectangle from X0 Y0 to X1 Y1
+    #[arg(long, num_args = 4, value_names = ["X0", "Y0", "X1", "Y1"], allow_negative_numbers = true)]
+    arena: Option<Vec<f32>>,
+    /// Rectangular obstacle from X0 Y0 to X1 Y1; may be given several times
+    #[arg(long, num_args = 4, value_names = ["X0", "Y0", "X1", "Y1"], allow_negative_numbers = true)]
+    obstacle: Vec<f32>,
+    /// What happens when the agent runs into an obstacle
+    #[arg(long, value_enum, default_value = "slide")]
+    collision: CollisionName,
+    /// Landmark at X Y with RADIUS and HEIGHT; may be given several times
+    #[arg(long, num_args = 4, value_names = ["X", "Y", "RADIUS", "HEIGHT"], allow_negative_numbers = true)]
+    landmark: Vec<f32>,
+    /// Learn views on the way out and use them to home alongside path integration
+    #[arg(long, value_enum)]
+    vision: Option<VisualMemoryName>,
+    /// Which way views are learned
+    #[arg(long, value_enum, default_value = "travel", requires = "vision")]
+    view_learning: ViewLearningName,
+    /// Share of visual steering in the motor output
+    #[arg(long, default_value_t = 0.5, requires = "vision")]
+    vision_weight: f32,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CompensationName {
+    None,
+    Linear,
+    Ephemeris,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum VisualMemoryName {
+    PerfectMemory,
+    Infomax,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ViewLearningName {
+    Travel,
+    FacingNest,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CollisionName {
+    Slide,
+    Stop,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RouteName {
+    RandomWalk,
+    VonMises,
+    Straight,
+    LShaped,
+    Waypoints,
+    Levy,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CaptureName {
+    Feeder,
+    Nest,
+}
+
+/// An error in the values of a flag, reported like clap's own.
+fn invalid(kind: ErrorKind, message: String) -> clap::Error {
+    Cli::command().error(kind, message)
+}
+
+/// Values of a flag that are taken in groups of `size`, rejecting a partial group.
+fn groups<'a>(
+    values: &'a [f32],
+    size: usize,
+    flag: &str,
+) -> Result<std::slice::ChunksExact<'a, f32>, clap::Error> {
+    if !values.len().is_multiple_of(size) {
+        return Err(invalid(
+            ErrorKind::WrongNumberOfValues,
+            format!(
+                "--{} takes values in groups of {}, but {} were provided",
+                flag,
+                size,
+                values.len()
+            ),
+        ));
+    }
+    Ok(values.chunks_exact(size))
+}
+
+impl SetupArgs {
+    fn setup(&self) -> Result<Setup, clap::Error> {
+        Ok(Setup {
+            outbound_steps: self.outbound_steps,
+            inbound_steps: self.inbound_steps,
+            acceleration_out: self.acceleration_out,
+            acceleration_in: self.acceleration_in,
+            vary_speed: !self.constant_speed,
+            record_memory: !self.no_memory,
+            displacement: self.displacement(),
+            stop: StopConditions {
+                radius: self.stop_radius,
+                memory: self.stop_memory,
+                search: self.search_onset()?,
+            },
+            probes: Probes {
+                populations: self.probe.clone(),
+                every: self.probe_every,
+            },
+            wind: self.wind(),
+            outbound: self.outbound()?,
+            time_step: TimeStep::new(self.dt, self.substeps)
+                .map_err(|e| invalid(ErrorKind::ValueValidation, e.to_string()))?,
+            outbound_altitude: match (self.outbound_altitude, &self.altitude_undulation) {
+                (Some(mean), Some(undulation)) => Altitude::Undulating {
+                    mean,
+                    amplitude: undulation[0],
+                    period: undulation[1],
+                },
+                (Some(height), None) => Altitude::Constant { height },
+                (None, _) => Altitude::Hold,
+            },
+            inbound_altitude: self
+                .inbound_altitude
+                .map_or(Altitude::Hold, |height| Altitude::Constant { height }),
+            senses: Senses {
+                optic_flow: match self.ventral_flow {
+                    Some(reference_altitude) => OpticFlow::Ventral(
+                        VentralFlow::new(reference_altitude, 0.1)
+                            .map_err(|e| invalid(ErrorKind::ValueValidation, e.to_string()))?,
+                    ),
+                    None => OpticFlow::Planar,
+                },
+                compass: self.compass(),
+            },
+            world: self.world()?,
+            vision: self.vision.map(|memory| Vision {
+                memory: match memory {
+                    VisualMemoryName::PerfectMemory => MemoryKind::PerfectMemory,
+                    VisualMemoryName::Infomax => MemoryKind::Infomax {
+                        learning_rate: 0.1,
+                        seed: None,
+                    },
+                },
+                learning: match self.view_learning {
+                    ViewLearningName::Travel => Learning::Travel,
+                    ViewLearningName::FacingNest => Learning::FacingNest,
+                },
+                weight: self.vision_weight,
+                resolution: 72,
+                every: 1,
+                scan: std::f32::consts::FRAC_PI_2,
+                gain: 0.5,
+            }),
+        })
+    }
+
+    fn search_onset(&self) -> Result<Option<SearchOnset>, clap::Error> {
+        let Some(ref values) = self.stop_search else {
+            return Ok(None);
+        };
+        let value = |e: &dyn std::fmt::Display| {
+            invalid(
+                ErrorKind::ValueValidation,
+                format!("invalid value for --stop-search: {}", e),
+            )
+        };
+        Ok(Some(SearchOnset {
+            window: values[0].parse::<usize>().map_err(|e| value(&e))?,
+            straightness: values[1].parse::<f32>().map_err(|e| value(&e))?,
+        }))
+    }
+
+    fn compass(&self) -> Compass {
+        let sun = match (&self.skylight, &self.solar_day) {
+            (Some(sun), _) => Sun::Fixed {
+                azimuth: sun[0],
+                elevation: sun[1],
+            },
+            (None, Some(day)) => Sun::Daily(SolarDay {
+                latitude: day[0],
+                declination: self.declination,
+                start: day[1],
+                hours_per_time: self.hours_per_time,
+            }),
+            (None, None) => return Compass::Heading,
+        };
+        Compass::Skylight(Skylight {
+            sun,
+            units: 16,
+            view_elevation: std::f32::consts::FRAC_PI_3,
+            max_degree: 0.75,
+            noise: self.polarization_noise,
+            cloud_cover: self.cloud_cover,
+            compensation: match self.sun_compensation {
+                CompensationName::None => Compensation::None,
+                CompensationName::Linear => Compensation::Linear {
+                    rate: self.compensation_rate,
+                },
+                CompensationName::Ephemeris => Compensation::Ephemeris,
+            },
+        })
+    }
+
+    fn world(&self) -> Result<World, clap::Error> {
+        let rectangle = |v: &[f32]| Obstacle::Rectangle {
+            min: Vector2::new(v[0].min(v[2]), v[1].min(v[3])),
+            max: Vector2::new(v[0].max(v[2]), v[1].max(v[3])),
+        };
+        Ok(World {
+            obstacles: groups(&self.obstacle, 4, "obstacle")?
+                .map(rectangle)
+                .collect(),
+            arena: self.arena.as_deref().map(rectangle),
+            collision: match self.collision {
+                CollisionName::Slide => Collision::Slide,
+                CollisionName::Stop => Collision::Stop,
+            },
+            landmarks: groups(&self.landmark, 4, "landmark")?
+                .map(|v| Landmark {
+                    position: Vector2::new(v[0], v[1]),
+                    radius: v[2],
+                    height: v[3],
+                })
+                .collect(),
+        })
+    }
+
+    fn outbound(&self) -> Result<Outbound, clap::Error> {
+        Ok(match self.route {
+            RouteName::RandomWalk => Outbound::RandomWalk,
+            RouteName::VonMises => Outbound::VonMises { kappa: self.kappa },
+            RouteName::Straight => Outbound::Straight,
+            RouteName::LShaped => Outbound::LShaped {
+                turn_at: self.turn_at,
+                angle: self.turn_angle,
+            },
+            RouteName::Waypoints => Outbound::Waypoints {
+                points: groups(&self.waypoints, 2, "waypoints")?
+                    .map(|point| Vector2::new(point[0], point[1]))
+                    .collect(),
+                radius: 5.0,
+                max_turn: 0.2,
+            },
+            RouteName::Levy => Outbound::Levy(
+                LevyFlight::new(self.levy_mu, self.levy_min_length)
+                    .map_err(|e| invalid(ErrorKind::ValueValidation, e.to_string()))?,
+            ),
+        })
+    }
+
+    fn wind(&self) -> Wind {
+        let mean = self
+            .wind
+            .as_ref()
+            .map_or(Vector2::zeros(), |v| Vector2::new(v[0], v[1]));
+        match self.gusts {
+            Some(ref gusts) => Wind::Gusting {
+                mean,
+                strength: gusts[0],
+                period: gusts[1],
+                seed: None,
+            },
+            None if self.wind.is_some() => Wind::Constant { velocity: mean },
+            None => Wind::Calm,
+        }
+    }
+
+    fn displacement(&self) -> Option<Displacement> {
+        if self.translate.is_none() && self.release_at.is_none() && self.rotate.is_none() {
+            return None;
+        }
+
+        let vector = |v: &Vec<f32>| Vector2::new(v[0], v[1]);
+        Some(Displacement {
+            capture: match self.capture_at {
+                CaptureName::Feeder => Capture::Feeder,
+                CaptureName::Nest => Capture::Nest,
+            },
+            translation: self.translate.as_ref().map_or(Vector2::zeros(), vector),
+            release_point: self.release_at.as_ref().map(vector),
+            rotation: self.rotate.unwrap_or(0.0),
+        })
+    }
+}
+
+#[derive(Args)]
+struct NoiseArgs {
+    #[arg(long, default_value_t = 0.1)]
+    activity_noise: f32,
+    #[arg(long, default_value_t = 0.0)]
+    weight_noise: f32,
+}
+
+impl NoiseArgs {
+    fn noise(&self) -> Noise {
+        Noise {
+            activity: self.activity_noise,
+            weight: self.weight_noise,
+        }
+    }
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Experiment spec (.json or .toml); replaces the model, setup and noise flags
+    #[arg(long)]
+    spec: Option<PathBuf>,
+    #[command(flatten)]
+    model: ModelArgs,
+    #[command(flatten)]
+    setup: SetupArgs,
+    #[command(flatten)]
+    noise: NoiseArgs,
+    #[arg(long, default_value_t = COMMON_SEED.unwrap())]
+    seed: u64,
+    /// Draw the seed from entropy instead
+    #[arg(long, conflicts_with = "seed")]
+    random_seed: bool,
+    /// Write the flight data here instead of to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Recorded track (CSV with t,x,y or t,heading,speed) to replay instead of the outbound route,
+    /// resampled at the simulation time step
+    #[arg(long)]
+    outbound_track: Option<PathBuf>,
+    /// Simulation distance per track distance unit
+    #[arg(long, default_value_t = 1.0, requires = "outbound_track")]
+    track_scale: f32,
+    /// Store a feeder vector at the end of the outbound route and travel back to it after homing
+    #[arg(long)]
+    revisit: bool,
+    /// Output format; jsonl and csv write one record per step while the trial runs,
+    /// without keeping the flight in memory
+    #[arg(long, value_enum, default_value = "json")]
+    format: Format,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// The complete flight data as a single JSON object
+    Json,
+    /// One JSON object per step, written as the flight goes
+    Jsonl,
+    /// One row per step with a header, written as the flight goes
+    Csv,
+}
+
+#[derive(Args)]
+struct ForageArgs {
+    /// Experiment spec (.json or .toml); replaces the model, setup and noise flags
+    #[arg(long)]
+    spec: Option<PathBuf>,
+    #[command(flatten)]
+    model: ModelArgs,
+    #[command(flatten)]
+    setup: SetupArgs,
+    #[command(flatten)]
+    noise: NoiseArgs,
+    #[arg(long, default_value_t = COMMON_SEED.unwrap())]
+    seed: u64,
+    #[arg(long, default_value_t = 10)]
+    trips: usize,
+    /// Go back to the last feeder with the vector memory instead of taking a new random route
+    #[arg(long)]
+    revisit: bool,
+    /// Distance within which the agent has arrived at the nest or feeder
+    #[arg(long, default_value_t = 10.0)]
+    arrival_radius: f32,
+    /// Clear the home vector from the memory on arriving at the nest
+    #[arg(long)]
+    reset: bool,
+    /// Move the memory this share of the way to holding no home vector on arriving at the nest
+    #[arg(long, conflicts_with = "reset")]
+    recalibrate: Option<f32>,
+    /// Put the agent back at the nest before every trip instead of setting out from where
+    /// the last one ended
+    #[arg(long)]
+    reset_to_nest: bool,
+    /// Include the flight data of every trip
+    #[arg(long)]
+    flights: bool,
+    /// Write the result here instead of to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl ForageArgs {
+    fn foraging(&self) -> Foraging {
+        Foraging {
+            trips: self.trips,
+            route: if self.revisit {
+                TripRoute::Revisit
+            } else {
+                TripRoute::Random
+            },
+            arrival_radius: self.arrival_radius,
+            recalibration: match (self.reset, self.recalibrate) {
+                (true, _) => Recalibration::Reset,
+                (false, Some(rate)) => Recalibration::Recalibrate { rate },
+                (false, None) => Recalibration::Keep,
+            },
+            policy: if self.reset_to_nest {
+                ForagingPolicy::ResetToNest
+            } else {
+                ForagingPolicy::Continue
+            },
+            record_flights: self.flights,
+        }
+    }
+}
+
+#[derive(Args)]
+struct SweepArgs {
+    #[command(flatten)]
+    model: ModelArgs,
+    #[command(flatten)]
+    setup: SetupArgs,
+    #[command(flatten)]
+    noise: NoiseArgs,
+    /// Values of beta, as lin:START:END:N
+    #[arg(long, default_value = "lin:0:0.9:20")]
+    beta_space: Space,
+    /// Values of h, as log:START:END:N in powers of ten
+    #[arg(long, default_value = "log:-4:0:20")]
+    h_space: Space,
+    /// Values of w0, as log:START:END:N in powers of ten
+    #[arg(long, default_value = "log:-40:0:20")]
+    w0_space: Space,
+    /// Number of trials per parameter point
+    #[arg(long, default_value_t = 30)]
+    samples: usize,
+    /// Master seed from which the seed of every trial is derived
+    #[arg(long, default_value_t = COMMON_SEED.unwrap())]
+    seed: u64,
+    /// Write the parameter points and the seed and statistics of every trial here as JSON
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    #[command(flatten)]
+    model: ModelArgs,
+    #[command(flatten)]
+    setup: SetupArgs,
+    #[command(flatten)]
+    noise: NoiseArgs,
+    /// Benchmark duration in seconds
+    #[arg(long, default_value_t = 10.0)]
+    duration: f32,
+}
+
+#[derive(Args)]
+struct AnalyzeArgs {
+    /// Flight data as written by `run`; read from stdin if absent
+    input: Option<PathBuf>,
+    /// Decode the home vector from the recorded memory, or the heading from probed TB1 activity,
+    /// instead
+    #[arg(long, value_enum)]
+    decode: Option<Decoded>,
+    /// Distance per unit of decoded memory; fitted to the flight if absent
+    #[arg(long, requires = "decode")]
+    scale: Option<f32>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Decoded {
+    HomeVector,
+    Heading,
+}
+
+#[derive(Clone)]
+enum Space {
+    Linear(f32, f32, usize),
+    Logarithmic(f32, f32, usize),
+}
+
+impl Space {
+    fn values(&self) -> Vec<f32> {
+        match *self {
+            Space::Linear(start, end, n) => Array::linspace(start, end, n).to_vec(),
+            Space::Logarithmic(start, end, n) => Array::logspace(10.0, start, end, n).to_vec(),
+        }
+    }
+}
+
+impl std::str::FromStr for Space {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let [kind, start, end, n] = parts[..] else {
+            return Err(format!("expected KIND:START:END:N, got {}", s));
+        };
+        let start = start.parse::<f32>().map_err(|e| e.to_string())?;
+        let end = end.parse::<f32>().map_err(|e| e.to_string())?;
+        let n = n.parse::<usize>().map_err(|e| e.to_string())?;
+        match kind {
+            "lin" => Ok(Space::Linear(start, end, n)),
+            "log" => Ok(Space::Logarithmic(start, end, n)),
+            _ => Err(format!("unknown space kind {} (expected lin or log)", kind)),
+        }
+    }
+}
+
+fn open_output(path: &Option<PathBuf>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    })
+}
+
+fn run(args: RunArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut experiment = match args.spec {
+        Some(ref spec) => Experiment::load(spec)?,
+        None => Experiment {
+            setup: args.setup.setup()?,
+            model: args.model.variant(),
+            noise: args.noise.noise(),
+            seed: if args.random_seed {
+                None
+            } else {
+                Some(args.seed)
+            },
+            protocol: None,
+        },
+    };
+    if let Some(ref path) = args.outbound_track {
+        let dt = experiment.setup.time_step.dt();
+        let states = Track::load(path)?.resample(dt, args.track_scale)?;
+        experiment.setup.outbound_steps = states.len();
+        experiment.protocol = Some(
+            experiment
+                .setup
+                .protocol_with_outbound(Control::Replay { states }),
+        );
+    }
+    if args.revisit {
+        let protocol = experiment
+            .protocol
+            .take()
+            .unwrap_or_else(|| experiment.setup.protocol());
+        experiment.protocol = Some(experiment.setup.with_revisit(protocol));
+    }
+
+    let mut output = open_output(&args.output)?;
+    match args.format {
+        Format::Json => {
+            let result = experiment.run();
+            serde_json::to_writer(&mut output, &result)?;
+            writeln!(output)?;
+        }
+        Format::Jsonl => {
+            let mut sink = JsonLinesSink::new(output);
+            experiment.stream(&mut sink);
+            sink.finish()?;
+        }
+        Format::Csv => {
+            let mut sink = CsvSink::new(output);
+            experiment.stream(&mut sink);
+            sink.finish()?;
+        }
+    }
+    Ok(())
+}
+
+fn forage(args: ForageArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let experiment = match args.spec {
+        Some(ref spec) => Experiment::load(spec)?,
+        None => Experiment {
+            setup: args.setup.setup()?,
+            model: args.model.variant(),
+            noise: args.noise.noise(),
+            seed: Some(args.seed),
+            protocol: None,
+        },
+    };
+
+    let result = experiment.forage(&args.foraging());
+    let mut output = open_output(&args.output)?;
+    serde_json::to_writer(&mut output, &result)?;
+    writeln!(output)?;
+    Ok(())
+}
+
+fn sweep(args: SweepArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut setup = args.setup.setup()?;
+    setup.record_memory = false;
+    let noise = args.noise.noise();
+
+    // Parameters the variant does not have are left at a single value
+    let space = |space: &Space, used: bool, value: f32| {
+        if used {
+            space.values()
+        } else {
+            vec![value]
+        }
+    };
+    let (beta_used, h_used) = match args.model.variant {
+        VariantName::Reference => (false, false),
+        VariantName::Affine => (true, false),
+        VariantName::Logistic | VariantName::LogisticAmp => (true, true),
+    };
+    let beta_space = space(&args.beta_space, beta_used, args.model.beta);
+    let h_space = space(&args.h_space, h_used, args.model.h);
+    let w0_space = space(&args.w0_space, h_used, args.model.w0);
+
+    let points = itertools::iproduct!(h_space.iter(), w0_space.iter(), beta_space.iter())
+        .map(|(&h, &w0, &beta)| {
+            let model = ModelArgs {
+                h,
+                w0,
+                beta,
+                ..args.model
+            };
+            Experiment {
+                setup: setup.clone(),
+                model: model.variant(),
+                noise: noise.clone(),
+                seed: None,
+                protocol: None,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let batch = run_batch(points, args.samples, args.seed);
+
+    let best = batch
+        .aggregates
+        .iter()
+        .enumerate()
+        .filter_map(|(point, aggregate)| {
+            Some((point, aggregate.metrics.get("min_distance_to_home")?))
+        })
+        .min_by(|(_, a), (_, b)| a.mean.total_cmp(&b.mean));
+    if let Some((point, summary)) = best {
+        let (low, high) = summary.confidence_interval;
+        writeln!(
+            io::stdout().lock(),
+            "{:?}: {} ({:.0}% CI {} to {})",
+            batch.points[point].model,
+            summary.mean,
+            Bootstrap::default().confidence * 100.0,
+            low,
+            high
+        )?;
+    }
+
+    if args.output.is_some() {
+        let mut output = open_output(&args.output)?;
+        serde_json::to_writer(&mut output, &batch)?;
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+fn bench(args: BenchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let duration = Duration::from_secs_f32(args.duration);
+    let then = Instant::now() + duration;
+
+    let experiment = Experiment {
+        setup: args.setup.setup()?,
+        model: args.model.variant(),
+        noise: args.noise.noise(),
+        seed: None,
+        protocol: None,
+    };
+
+    let mut times = 0;
+    while Instant::now() < then {
+        experiment.run();
+        times += 1;
+    }
+
+    writeln!(
+        io::stdout().lock(),
+        "rust: simulated {} flights per second",
+        (times as f32) / duration.as_secs_f32()
+    )?;
+    Ok(())
+}
+
+fn analyze(args: AnalyzeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = String::new();
+    match args.input {
+        Some(ref path) => File::open(path)?.read_to_string(&mut input)?,
+        None => io::stdin().read_to_string(&mut input)?,
+    };
+
+    let flight: FlightData = serde_json::from_str(&input)?;
+    let mut output = io::stdout().lock();
+    match args.decode {
+        Some(Decoded::HomeVector) => {
+            let decoding = HomeVectorDecoding::analyze(&flight, args.scale)
+                .ok_or("the flight has no memory record to decode")?;
+            serde_json::to_writer(&mut output, &decoding)?;
+        }
+        Some(Decoded::Heading) => {
+            let decoding = HeadingDecoding::analyze(&flight)
+                .ok_or("the flight has no TB1 probe to decode; run with --probe tb1")?;
+            serde_json::to_writer(&mut output, &decoding)?;
+        }
+        None => serde_json::to_writer(&mut output, &FlightStats::analyze(&flight))?,
+    }
+    writeln!(output)?;
+    Ok(())
+}
+
+/// Whether the error is the reader of the output going away, as when piped into `head`.
+fn broken_pipe(e: &(dyn std::error::Error + 'static)) -> bool {
+    let kind = match e.downcast_ref::<serde_json::Error>() {
+        Some(e) => e.io_error_kind(),
+        None => e.downcast_ref::<io::Error>().map(io::Error::kind),
+    };
+    kind == Some(io::ErrorKind::BrokenPipe)
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Run(args) => run(args),
+        Command::Forage(args) => forage(args),
+        Command::Sweep(args) => sweep(args),
+        Command::Bench(args) => bench(args),
+        Command::Analyze(args) => analyze(args),
+    };
+
+    if let Err(e) = result {
+        if let Some(e) = e.downcast_ref::<clap::Error>() {
+            e.exit();
+        }
+        if broken_pipe(&*e) {
+            return;
+        }
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
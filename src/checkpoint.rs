@@ -0,0 +1,183 @@
+//! Snapshots of trials in progress, for forking one outbound run into many homing experiments
+//! and for resuming long runs after a crash.
+
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    model::{Config, CX},
+    movement::PhysicalState,
+    protocol::{self, Phase, Progress},
+    sink::Sink,
+    FlightData, Setup,
+};
+
+/// The complete network, including its random state, together with the protocol,
+/// the position within it and the flight so far.
+/// The agent's current physical state is the last one in the flight,
+/// and it has not been fed to the network yet.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Checkpoint<C: Config> {
+    pub cx: CX<C>,
+    pub protocol: Vec<Phase>,
+    pub progress: Progress,
+    pub flight: FlightData,
+}
+
+impl<C: Config> Checkpoint<C> {
+    pub fn new(setup: &Setup, cx: CX<C>, protocol: Vec<Phase>) -> Self {
+        Checkpoint {
+            cx,
+            flight: FlightData::new(setup, &protocol),
+            protocol,
+            progress: Progress::default(),
+        }
+    }
+
+    /// Replays the outbound route and checkpoints the agent at its end, ready to home.
+    pub fn after_outbound(setup: &Setup, cx: CX<C>, outbound: Vec<PhysicalState>) -> Self {
+        let protocol = setup.protocol_with_outbound(protocol::Control::Replay { states: outbound });
+        let mut checkpoint = Self::new(setup, cx, protocol);
+        checkpoint.run_until_phase(1);
+        checkpoint
+    }
+
+    /// A copy to run on with its own activity and sensing noise, drawn from `seed`.
+    pub fn fork(&self, seed: u64) -> Self
+    where
+        C: Clone,
+    {
+        let mut fork = self.clone();
+        fork.cx.random_mut().reseed(seed);
+        fork
+    }
+
+    /// Number of steps so far.
+    pub fn step(&self) -> usize {
+        self.flight.step_count()
+    }
+
+    pub fn physical_state(&self) -> Option<&PhysicalState> {
+        self.flight.physical_states.last()
+    }
+
+    /// Runs at most `steps` more steps. Returns whether the protocol is complete.
+    pub fn advance(&mut self, steps: usize) -> bool {
+        self.advance_with(steps, &mut ())
+    }
+
+    /// Like [`Checkpoint::advance`], passing every step to the sink.
+    pub fn advance_with(&mut self, steps: usize, sink: &mut impl Sink) -> bool {
+        protocol::advance_with(
+            &mut self.cx,
+            &self.protocol,
+            &mut self.flight,
+            &mut self.progress,
+            steps,
+            sink,
+        )
+    }
+
+    /// Runs all phases before the one with the given index.
+    pub fn run_until_phase(&mut self, phase: usize) {
+        while self.progress.phase < phase && !self.advance(1) {}
+    }
+
+    /// Runs the rest of the protocol and returns the complete flight.
+    pub fn finish(mut self) -> FlightData {
+        self.advance(usize::MAX);
+        self.flight
+    }
+
+    pub fn save(&self, writer: impl Write) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, self)
+    }
+
+    pub fn load(reader: impl Read) -> serde_json::Result<Self> {
+        serde_json::from_reader(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        create_weight_logistic_cx, experiment::Experiment,
+        model::memory::weights::LogisticDynamics, protocol::run_protocol, util::Random,
+        WeightConfig,
+    };
+
+    const SPEC: &str = r#"
+        [setup]
+        outbound_steps = 300
+        inbound_steps = 300
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = true
+        time_step = { dt = 0.5, substeps = 2 }
+
+        [setup.senses.compass]
+        type = "skylight"
+        sun = { type = "fixed", azimuth = 1.0, elevation = 0.5 }
+        noise = 0.1
+        cloud_cover = 0.2
+
+        [model]
+        variant = "logistic"
+        h = 0.0048329304
+        w0 = 6.1584935e-5
+        beta = 0.6
+    "#;
+
+    fn setup() -> Setup {
+        Experiment::from_toml(SPEC).unwrap().setup
+    }
+
+    fn cx() -> CX<WeightConfig<LogisticDynamics>> {
+        let random = Random::new(0.1, 0.01, Some(8));
+        create_weight_logistic_cx(random, 0.0048329304, 6.1584935e-5, 0.6)
+    }
+
+    fn json(flight: &FlightData) -> String {
+        serde_json::to_string(flight).unwrap()
+    }
+
+    #[test]
+    fn restored_checkpoint_continues_identically() {
+        let setup = setup();
+        let protocol = setup.protocol();
+        let uninterrupted = run_protocol(&mut cx(), &setup, &protocol);
+
+        let mut checkpoint = Checkpoint::new(&setup, cx(), protocol);
+        checkpoint.advance(setup.outbound_steps + 50);
+        let mut saved = Vec::new();
+        checkpoint.save(&mut saved).unwrap();
+        let restored: Checkpoint<WeightConfig<LogisticDynamics>> =
+            Checkpoint::load(saved.as_slice()).unwrap();
+
+        assert_eq!(json(&restored.finish()), json(&uninterrupted));
+        assert_eq!(json(&checkpoint.finish()), json(&uninterrupted));
+    }
+
+    #[test]
+    fn forks_with_other_seeds_home_differently() {
+        let setup = setup();
+        let mut cx = cx();
+        let outbound = setup.generate_outbound(cx.random_mut());
+        let checkpoint = Checkpoint::after_outbound(&setup, cx, outbound);
+        let home = |seed: u64| checkpoint.fork(seed).finish();
+
+        let (first, second) = (home(1), home(2));
+        assert_eq!(json(&first), json(&home(1)));
+        let (first, second) = (
+            first.physical_states.split_at(checkpoint.step()),
+            second.physical_states.split_at(checkpoint.step()),
+        );
+        let json = |states: &[PhysicalState]| serde_json::to_string(states).unwrap();
+        assert_eq!(json(first.0), json(second.0));
+        assert_ne!(json(first.1), json(second.1));
+    }
+}
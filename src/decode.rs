@@ -0,0 +1,280 @@
+//! Decoding what the network represents from recorded activity.
+//!
+//! Directions are compass angles as used by `PhysicalState::heading`:
+//! an angle `a` points along `(sin a, cos a)`.
+
+use nalgebra::{SVector, Vector2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    model::constants::{N_CPU4, N_TB1},
+    probe::Population,
+    FlightData,
+};
+
+/// Preferred direction of each TB1 column, which the CPU4 cells of both hemispheres inherit.
+pub fn column_direction(column: usize) -> f32 {
+    std::f32::consts::TAU * (column % N_TB1) as f32 / N_TB1 as f32
+}
+
+fn direction(angle: f32) -> Vector2<f32> {
+    Vector2::new(angle.sin(), angle.cos())
+}
+
+/// The compass angle a vector points along.
+pub fn compass_angle(vector: &Vector2<f32>) -> f32 {
+    vector.x.atan2(vector.y)
+}
+
+/// Wraps an angle into [-pi, pi).
+pub fn wrap_angle(angle: f32) -> f32 {
+    (angle + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI
+}
+
+/// Home vector encoded by CPU4 memory, in memory units.
+///
+/// The population vector of the memory over the columns' preferred directions
+/// points along the outbound route, so the home vector is its opposite.
+/// Works on the memory of `AbstractCpu4` as well as on the pontine weight diagonal.
+pub fn decode_home_vector(memory: &SVector<f32, N_CPU4>) -> Vector2<f32> {
+    -memory
+        .iter()
+        .enumerate()
+        .map(|(cell, &activity)| direction(column_direction(cell)) * activity)
+        .sum::<Vector2<f32>>()
+}
+
+/// Decoded and true home vector after the network has seen one physical state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DecodedHomeVector {
+    /// Index of the physical state in the flight.
+    pub step: usize,
+    /// In units of distance, using the decoding's scale.
+    pub decoded: Vector2<f32>,
+    pub actual: Vector2<f32>,
+    /// Signed angle from the true to the decoded home vector.
+    pub angular_error: f32,
+    /// Distance between the decoded and the true home vector.
+    pub error: f32,
+}
+
+/// How accurately the memory tracked the home vector over a flight.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HomeVectorDecoding {
+    /// Distance per unit of decoded memory.
+    pub scale: f32,
+    pub mean_absolute_angular_error: f32,
+    pub rms_error: f32,
+    pub steps: Vec<DecodedHomeVector>,
+}
+
+impl HomeVectorDecoding {
+    /// Decodes every recorded memory state in the flight, comparing it to the home vector
+    /// the agent actually had at that point.
+    ///
+    /// The memory's scale depends on the model, so unless given it is fitted by least squares.
+    /// Returns `None` if no memory was recorded.
+    pub fn analyze(flight: &FlightData, scale: Option<f32>) -> Option<HomeVectorDecoding> {
+        let pairs: Vec<(usize, Vector2<f32>, Vector2<f32>)> = flight
+            .memory_record
+            .as_ref()?
+            .iter()
+            .enumerate()
+            .filter_map(|(step, memory)| {
+                let actual = -flight.physical_states.get(step)?.position;
+                Some((step, decode_home_vector(memory.as_ref()?), actual))
+            })
+            .collect();
+        if pairs.is_empty() {
+            return None;
+        }
+
+        let scale = scale.unwrap_or_else(|| {
+            let (dot, norm) = pairs
+                .iter()
+                .fold((0.0, 0.0), |(dot, norm), (_, decoded, actual)| {
+                    (dot + decoded.dot(actual), norm + decoded.norm_squared())
+                });
+            if norm > 0.0 {
+                dot / norm
+            } else {
+                0.0
+            }
+        });
+
+        let steps: Vec<DecodedHomeVector> = pairs
+            .into_iter()
+            .map(|(step, decoded, actual)| {
+                let decoded = decoded * scale;
+                DecodedHomeVector {
+                    step,
+                    decoded,
+                    actual,
+                    angular_error: wrap_angle(compass_angle(&decoded) - compass_angle(&actual)),
+                    error: (decoded - actual).magnitude(),
+                }
+            })
+            .collect();
+
+        let n = steps.len() as f32;
+        Some(HomeVectorDecoding {
+            scale,
+            mean_absolute_angular_error: steps
+                .iter()
+                .map(|step| step.angular_error.abs())
+                .sum::<f32>()
+                / n,
+            rms_error: (steps.iter().map(|step| step.error.powi(2)).sum::<f32>() / n).sqrt(),
+            steps,
+        })
+    }
+}
+
+/// Heading encoded by the TB1 ring attractor.
+///
+/// TB1 is least active in the columns facing the current heading,
+/// so the population vector points the opposite way.
+pub fn decode_heading(tb1: &SVector<f32, N_TB1>) -> f32 {
+    let population = tb1
+        .iter()
+        .enumerate()
+        .map(|(column, &activity)| direction(column_direction(column)) * activity)
+        .sum::<Vector2<f32>>();
+    compass_angle(&-population).rem_euclid(std::f32::consts::TAU)
+}
+
+/// Decoded and true heading when the network saw one physical state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DecodedHeading {
+    /// Index of the physical state in the flight.
+    pub step: usize,
+    pub decoded: f32,
+    pub actual: f32,
+    /// Signed angle from the true to the decoded heading.
+    pub error: f32,
+}
+
+/// How accurately the compass tracked the heading over a flight, in circular statistics.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeadingDecoding {
+    /// Circular mean of the error, i.e. the compass's bias.
+    pub mean_error: f32,
+    /// Length of the mean resultant vector of the errors, 1 for a perfect compass.
+    pub mean_resultant_length: f32,
+    /// `sqrt(-2 ln R)` of the mean resultant length `R`.
+    pub circular_std_dev: f32,
+    pub mean_absolute_error: f32,
+    pub rms_error: f32,
+    pub steps: Vec<DecodedHeading>,
+}
+
+impl HeadingDecoding {
+    /// Decodes the heading from every TB1 sample in the flight.
+    /// Returns `None` unless TB1 was probed.
+    pub fn analyze(flight: &FlightData) -> Option<HeadingDecoding> {
+        let probe_record = flight.probe_record.as_ref()?;
+        let tb1 = probe_record.activities.get(&Population::Tb1)?;
+
+        let steps: Vec<DecodedHeading> = probe_record
+            .steps
+            .iter()
+            .zip(tb1)
+            .filter_map(|(&step, tb1)| {
+                let actual = flight.physical_states.get(step)?.heading;
+                let decoded = decode_heading(&SVector::from_column_slice(tb1));
+                Some(DecodedHeading {
+                    step,
+                    decoded,
+                    actual,
+                    error: wrap_angle(decoded - actual),
+                })
+            })
+            .collect();
+        if steps.is_empty() {
+            return None;
+        }
+
+        let n = steps.len() as f32;
+        let resultant = steps
+            .iter()
+            .map(|step| direction(step.error))
+            .sum::<Vector2<f32>>()
+            / n;
+        let mean_resultant_length = resultant.magnitude();
+        Some(HeadingDecoding {
+            mean_error: compass_angle(&resultant),
+            mean_resultant_length,
+            circular_std_dev: (-2.0 * mean_resultant_length.ln()).sqrt(),
+            mean_absolute_error: steps.iter().map(|step| step.error.abs()).sum::<f32>() / n,
+            rms_error: (steps.iter().map(|step| step.error.powi(2)).sum::<f32>() / n).sqrt(),
+            steps,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::experiment::Experiment;
+
+    const SPEC: &str = r#"
+        seed = 9
+
+        [setup]
+        outbound_steps = 500
+        inbound_steps = 0
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = true
+        probes = { populations = ["tb1"] }
+
+        [model]
+        variant = "reference"
+    "#;
+
+    #[test]
+    fn decodes_a_known_home_vector() {
+        // Cosine tuning around the outbound direction, on top of a baseline that cancels out
+        let outbound = 2.0;
+        let memory = SVector::<f32, N_CPU4>::from_fn(|cell, _| {
+            0.5 + 0.1 * (column_direction(cell) - outbound).cos()
+        });
+        let expected = -direction(outbound) * 0.1 * N_CPU4 as f32 / 2.0;
+        assert!((decode_home_vector(&memory) - expected).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn memory_tracks_the_home_vector() {
+        let flight = Experiment::from_toml(SPEC).unwrap().run();
+        let decoding = HomeVectorDecoding::analyze(&flight, None).unwrap();
+        // Every state but the last has been fed to the network
+        assert_eq!(decoding.steps.len(), 499);
+        assert!(decoding.mean_absolute_angular_error < 0.15);
+
+        let last = decoding.steps.last().unwrap();
+        assert_eq!(last.actual, -flight.physical_states[498].position);
+        assert!(last.error < 0.1 * last.actual.magnitude());
+    }
+
+    #[test]
+    fn decodes_a_known_heading() {
+        for heading in [0.0, 1.0, 3.5, 6.2] {
+            // TB1 is least active facing the heading
+            let tb1 = SVector::<f32, N_TB1>::from_fn(|column, _| {
+                1.0 - (column_direction(column) - heading).cos()
+            });
+            assert!(wrap_angle(decode_heading(&tb1) - heading).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn tb1_tracks_the_heading() {
+        let flight = Experiment::from_toml(SPEC).unwrap().run();
+        let decoding = HeadingDecoding::analyze(&flight).unwrap();
+        assert_eq!(decoding.steps.len(), 499);
+        assert!(decoding.mean_error.abs() < 0.05);
+        assert!(decoding.mean_resultant_length > 0.95);
+        assert!(decoding.mean_absolute_error < 0.2);
+    }
+}
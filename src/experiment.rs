@@ -0,0 +1,334 @@
+//! Experiment specifications, so that trials can be described as JSON or TOML data files
+//! instead of hard-coded in example binaries.
+//!
+//! A minimal TOML spec looks like this:
+//!
+//! ```toml
+//! seed = 64172527321326
+//!
+//! [setup]
+//! outbound_steps = 1500
+//! inbound_steps = 1500
+//! acceleration_out = 0.15
+//! acceleration_in = 0.1
+//! vary_speed = true
+//! record_memory = true
+//!
+//! [model]
+//! variant = "logistic"
+//! h = 0.0048329304
+//! w0 = 6.1584935e-5
+//! beta = 0.6631579
+//!
+//! [noise]
+//! activity = 0.1
+//! weight = 0.0
+//! ```
+
+use std::{fmt, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    create_reference_cx, create_weight_affine_cx, create_weight_logistic_amp_cx,
+    create_weight_logistic_cx,
+    foraging::{Foraging, ForagingResult},
+    model::{Config, CX},
+    protocol::{run_protocol_streaming, run_protocol_with, Phase},
+    sink::Sink,
+    util::Random,
+    FlightData, Setup,
+};
+
+/// Model variant and its parameters.
+///
+/// `turn_sharpness` overrides the variant's default when given.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "variant", rename_all = "snake_case")]
+pub enum Variant {
+    Reference {
+        #[serde(default)]
+        turn_sharpness: Option<f32>,
+    },
+    Affine {
+        beta: f32,
+        #[serde(default)]
+        turn_sharpness: Option<f32>,
+    },
+    Logistic {
+        h: f32,
+        w0: f32,
+        beta: f32,
+        #[serde(default)]
+        turn_sharpness: Option<f32>,
+    },
+    LogisticAmp {
+        h: f32,
+        w0: f32,
+        beta: f32,
+        #[serde(default)]
+        turn_sharpness: Option<f32>,
+    },
+}
+
+impl Variant {
+    pub fn turn_sharpness(&self) -> Option<f32> {
+        match *self {
+            Variant::Reference { turn_sharpness }
+            | Variant::Affine { turn_sharpness, .. }
+            | Variant::Logistic { turn_sharpness, .. }
+            | Variant::LogisticAmp { turn_sharpness, .. } => turn_sharpness,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Noise {
+    pub activity: f32,
+    pub weight: f32,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            activity: 0.1,
+            weight: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Experiment {
+    pub setup: Setup,
+    pub model: Variant,
+    #[serde(default)]
+    pub noise: Noise,
+    /// Seed for the random number generator; drawn from entropy if absent.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Phases to run instead of the outbound route and homing described by `setup`.
+    #[serde(default)]
+    pub protocol: Option<Vec<Phase>>,
+}
+
+#[derive(Debug)]
+pub enum SpecError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Toml(toml::de::Error),
+    UnknownFormat(String),
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecError::Io(e) => write!(f, "could not read experiment spec: {}", e),
+            SpecError::Json(e) => write!(f, "invalid JSON experiment spec: {}", e),
+            SpecError::Toml(e) => write!(f, "invalid TOML experiment spec: {}", e),
+            SpecError::UnknownFormat(path) => write!(
+                f,
+                "unknown experiment spec format for {} (expected .json or .toml)",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+impl Experiment {
+    pub fn from_json(spec: &str) -> Result<Experiment, SpecError> {
+        serde_json::from_str(spec).map_err(SpecError::Json)
+    }
+
+    pub fn from_toml(spec: &str) -> Result<Experiment, SpecError> {
+        toml::from_str(spec).map_err(SpecError::Toml)
+    }
+
+    /// Loads a spec, choosing the format from the file extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Experiment, SpecError> {
+        let path = path.as_ref();
+        let spec = std::fs::read_to_string(path).map_err(SpecError::Io)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&spec),
+            Some("toml") => Self::from_toml(&spec),
+            _ => Err(SpecError::UnknownFormat(path.display().to_string())),
+        }
+    }
+
+    pub fn with_seed(&self, seed: u64) -> Experiment {
+        Experiment {
+            seed: Some(seed),
+            ..self.clone()
+        }
+    }
+
+    pub fn protocol(&self) -> Vec<Phase> {
+        match self.protocol {
+            Some(ref protocol) => protocol.clone(),
+            None => self.setup.protocol(),
+        }
+    }
+
+    /// Builds the CX for the chosen variant and runs the protocol with it.
+    pub fn run(&self) -> FlightData {
+        self.run_with(&mut ())
+    }
+
+    /// Like [`Experiment::run`], passing every step to the sink as it happens.
+    pub fn run_with(&self, sink: &mut impl Sink) -> FlightData {
+        self.run_flight(sink, false)
+    }
+
+    /// Like [`Experiment::run_with`], keeping only the latest steps of the flight,
+    /// for output that all goes to the sink.
+    pub fn stream(&self, sink: &mut impl Sink) -> FlightData {
+        self.run_flight(sink, true)
+    }
+
+    fn run_flight(&self, sink: &mut impl Sink, streaming: bool) -> FlightData {
+        let random = Random::new(self.noise.activity, self.noise.weight, self.seed);
+
+        match self.model {
+            Variant::Reference { .. } => self.trial(create_reference_cx(random), sink, streaming),
+            Variant::Affine { beta, .. } => {
+                self.trial(create_weight_affine_cx(random, beta), sink, streaming)
+            }
+            Variant::Logistic { h, w0, beta, .. } => self.trial(
+                create_weight_logistic_cx(random, h, w0, beta),
+                sink,
+                streaming,
+            ),
+            Variant::LogisticAmp { h, w0, beta, .. } => self.trial(
+                create_weight_logistic_amp_cx(random, h, w0, beta),
+                sink,
+                streaming,
+            ),
+        }
+    }
+
+    /// Builds the CX for the chosen variant and sends it on repeated foraging trips.
+    /// The protocol, if any, is not used.
+    pub fn forage(&self, foraging: &Foraging) -> ForagingResult {
+        let random = Random::new(self.noise.activity, self.noise.weight, self.seed);
+
+        match self.model {
+            Variant::Reference { .. } => self.trips(create_reference_cx(random), foraging),
+            Variant::Affine { beta, .. } => {
+                self.trips(create_weight_affine_cx(random, beta), foraging)
+            }
+            Variant::Logistic { h, w0, beta, .. } => {
+                self.trips(create_weight_logistic_cx(random, h, w0, beta), foraging)
+            }
+            Variant::LogisticAmp { h, w0, beta, .. } => {
+                self.trips(create_weight_logistic_amp_cx(random, h, w0, beta), foraging)
+            }
+        }
+    }
+
+    fn trial<C: Config>(&self, mut cx: CX<C>, sink: &mut impl Sink, streaming: bool) -> FlightData {
+        self.tune(&mut cx);
+        let protocol = self.protocol();
+        if streaming {
+            run_protocol_streaming(&mut cx, &self.setup, &protocol, sink)
+        } else {
+            run_protocol_with(&mut cx, &self.setup, &protocol, sink)
+        }
+    }
+
+    fn trips<C: Config>(&self, mut cx: CX<C>, foraging: &Foraging) -> ForagingResult {
+        self.tune(&mut cx);
+        foraging.run(&mut cx, &self.setup)
+    }
+
+    fn tune<C: Config>(&self, cx: &mut CX<C>) {
+        if let Some(turn_sharpness) = self.model.turn_sharpness() {
+            cx.turn_sharpness = turn_sharpness;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        seed = 11
+
+        [setup]
+        outbound_steps = 200
+        inbound_steps = 200
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = true
+
+        [model]
+        variant = "logistic"
+        h = 0.0048329304
+        w0 = 6.1584935e-5
+        beta = 0.6631579
+
+        [noise]
+        activity = 0.1
+        weight = 0.01
+    "#;
+
+    const JSON: &str = r#"{
+        "seed": 11,
+        "setup": {
+            "outbound_steps": 200,
+            "inbound_steps": 200,
+            "acceleration_out": 0.15,
+            "acceleration_in": 0.1,
+            "vary_speed": true,
+            "record_memory": true
+        },
+        "model": { "variant": "logistic", "h": 0.0048329304, "w0": 6.1584935e-5, "beta": 0.6631579 },
+        "noise": { "activity": 0.1, "weight": 0.01 }
+    }"#;
+
+    fn json(flight: &FlightData) -> String {
+        serde_json::to_string(flight).unwrap()
+    }
+
+    #[test]
+    fn toml_and_json_specs_run_the_same_trial() {
+        let flight = Experiment::from_toml(TOML).unwrap().run();
+        assert_eq!(
+            json(&flight),
+            json(&Experiment::from_json(JSON).unwrap().run())
+        );
+        assert_eq!(flight.physical_states.len(), 400);
+        assert_eq!(flight.memory_record.map(|record| record.len()), Some(400));
+    }
+
+    #[test]
+    fn seeds_decide_the_trial() {
+        let experiment = Experiment::from_toml(TOML).unwrap();
+        let run = |seed: u64| json(&experiment.with_seed(seed).run());
+        assert_eq!(run(11), json(&experiment.run()));
+        assert_ne!(run(11), run(12));
+    }
+
+    #[test]
+    fn every_variant_builds_and_runs() {
+        let models = [
+            r#"variant = "reference""#,
+            r#"variant = "affine"
+               beta = 0.6"#,
+            r#"variant = "logistic_amp"
+               h = 0.0048329304
+               w0 = 6.1584935e-5
+               beta = 0.6
+               turn_sharpness = 2.0"#,
+        ];
+        for model in models {
+            let spec = TOML.split("[model]").next().unwrap().to_owned() + "[model]\n" + model;
+            let flight = Experiment::from_toml(&spec).unwrap().run();
+            assert_eq!(flight.physical_states.len(), 400);
+        }
+        assert!(Experiment::from_toml(&TOML.replace("logistic", "quadratic")).is_err());
+    }
+}
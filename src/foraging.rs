@@ -0,0 +1,263 @@
+//! Repeated foraging trips by the same agent, for experiments on memory drift.
+//!
+//! Every trip goes out to a feeder, feeds there and homes. The network, with its memory,
+//! vector memory and random state, carries over from one trip to the next, and each trip
+//! starts where and when the last one ended, which is away from the nest if the agent did not
+//! make it home; with `policy = "reset_to_nest"`, the agent is put back at the nest instead.
+//! Once the agent is back within `arrival_radius` of the nest, its memory can be recalibrated;
+//! an agent that does not make it home starts the next trip with its memory as it is.
+//! On a revisit, the agent feeds wherever its travel to the feeder ends. As JSON:
+//!
+//! ```json
+//! { "trips": 50, "route": "revisit", "arrival_radius": 10.0, "recalibration": { "type": "reset" } }
+//! ```
+
+use nalgebra::Vector2;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    model::{memory::MemoryRecorder, memory::Recalibration as _, Config, CX},
+    protocol::{
+        continue_protocol, outbound_phases, run_protocol, ControlKind, Phase, StopConditions,
+        StopReason,
+    },
+    stats::FlightStats,
+    FlightData, Setup,
+};
+
+/// How the agent gets to the feeder.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TripRoute {
+    /// A new random route on every trip, so that every trip finds a new feeder.
+    #[default]
+    Random,
+    /// A random route on the first trip, then back to the last feeder with the vector memory.
+    Revisit,
+}
+
+/// Where the agent starts each trip after the first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForagingPolicy {
+    /// Wherever the last trip ended, with the clock running on.
+    #[default]
+    Continue,
+    /// At rest at the nest, with the clock back at 0, as if it were a new flight.
+    ResetToNest,
+}
+
+/// What happens to the memory when the agent arrives at the nest.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Recalibration {
+    /// Keeps the memory, so that errors accumulate from trip to trip.
+    #[default]
+    Keep,
+    /// Clears the home vector from the memory.
+    Reset,
+    /// Moves the memory a `rate` of the way to holding no home vector.
+    Recalibrate { rate: f32 },
+}
+
+impl Recalibration {
+    pub fn apply<C: Config>(&self, cx: &mut CX<C>) {
+        match *self {
+            Recalibration::Keep => {}
+            Recalibration::Reset => C::Recalibration::recalibrate(cx, 1.0),
+            Recalibration::Recalibrate { rate } => C::Recalibration::recalibrate(cx, rate),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Foraging {
+    pub trips: usize,
+    #[serde(default)]
+    pub route: TripRoute,
+    /// Distance within which the agent has arrived at the nest, or at the feeder on a revisit.
+    #[serde(default = "default_arrival_radius")]
+    pub arrival_radius: f32,
+    #[serde(default)]
+    pub recalibration: Recalibration,
+    #[serde(default)]
+    pub policy: ForagingPolicy,
+    /// Whether to keep the flight data of every trip.
+    #[serde(default)]
+    pub record_flights: bool,
+}
+
+fn default_arrival_radius() -> f32 {
+    10.0
+}
+
+/// How one trip went, and the state of the memory at its end.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TripStats {
+    pub trip: usize,
+    /// Where the agent set out from.
+    pub start: Vector2<f32>,
+    /// Whether the agent was put back at the nest before the trip.
+    pub reset_to_nest: bool,
+    /// Where the agent fed.
+    pub feeder: Option<Vector2<f32>>,
+    /// Closest the agent came to the feeder it set out for, on a revisit.
+    pub min_distance_to_feeder: Option<f32>,
+    /// Whether the agent made it back to within the arrival radius of the nest.
+    pub arrived: bool,
+    pub flight: FlightStats,
+    /// Spread of the memory across CPU4 cells (max - min) at the end of the trip,
+    /// before any recalibration: what is left of the home vector.
+    pub residual_memory: f32,
+    /// Mean memory across CPU4 cells at the end of the trip, e.g. the saturation of the weights.
+    pub memory_level: f32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ForagingResult {
+    pub trips: Vec<TripStats>,
+    /// Flight data of every trip, if recorded.
+    #[serde(default)]
+    pub flights: Option<Vec<FlightData>>,
+}
+
+impl Foraging {
+    /// The protocol of a trip: to the feeder, a feed, and homing.
+    /// Revisits head for the feeder at `feeder`, where the agent fed on the trip before.
+    pub fn protocol(&self, setup: &Setup, trip: usize, feeder: Option<Vector2<f32>>) -> Vec<Phase> {
+        let setup = &Setup {
+            stop: StopConditions {
+                radius: Some(self.arrival_radius),
+                ..setup.stop.clone()
+            },
+            ..setup.clone()
+        };
+        let phases = outbound_phases(setup, setup.outbound_route(), feeder);
+
+        let outbound = match self.route {
+            TripRoute::Revisit if trip > 0 => Phase {
+                name: "to_feeder".into(),
+                learn_views: phases.outbound.learn_views,
+                ..phases.revisit
+            },
+            _ => phases.outbound,
+        };
+        vec![outbound, phases.feed, phases.homing]
+    }
+
+    /// Runs all trips with the same network.
+    pub fn run<C: Config>(&self, cx: &mut CX<C>, setup: &Setup) -> ForagingResult {
+        let mut trips = Vec::with_capacity(self.trips);
+        let mut flights = self.record_flights.then(Vec::new);
+        let mut feeder: Option<Vector2<f32>> = None;
+        let mut previous: Option<FlightData> = None;
+
+        for trip in 0..self.trips {
+            let protocol = self.protocol(setup, trip, feeder);
+            let reset_to_nest = previous.is_some() && self.policy == ForagingPolicy::ResetToNest;
+            let flight = match previous {
+                Some(ref previous) if !reset_to_nest => {
+                    continue_protocol(cx, setup, &protocol, previous)
+                }
+                _ => run_protocol(cx, setup, &protocol),
+            };
+
+            let min_distance_to_feeder = feeder.and_then(|feeder| {
+                flight
+                    .steps_with(ControlKind::ToFeeder)
+                    .map(|step| (flight.physical_states[step].position - feeder).magnitude())
+                    .min_by(f32::total_cmp)
+            });
+            let arrived = flight
+                .phases
+                .last()
+                .and_then(|phase| phase.termination)
+                .is_some_and(|termination| termination.reason == StopReason::ReachedHome);
+            let memory = C::MemoryRecorder::record(cx);
+            if arrived {
+                self.recalibration.apply(cx);
+            }
+
+            feeder = flight.feeders.last().copied();
+            trips.push(TripStats {
+                trip,
+                start: flight.initial.position,
+                reset_to_nest,
+                feeder,
+                min_distance_to_feeder,
+                arrived,
+                flight: FlightStats::analyze(&flight),
+                residual_memory: memory.max() - memory.min(),
+                memory_level: memory.mean(),
+            });
+            if let Some(ref mut flights) = flights {
+                flights.push(flight.clone());
+            }
+            previous = Some(flight);
+        }
+
+        ForagingResult { trips, flights }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::experiment::Experiment;
+
+    /// Homing too short to make it back, so that every trip ends out in the field.
+    const SPEC: &str = r#"
+        seed = 6
+
+        [setup]
+        outbound_steps = 300
+        inbound_steps = 10
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = true
+        probes = { populations = ["tb1", "cpu4"], every = 1 }
+
+        [model]
+        variant = "reference"
+    "#;
+
+    fn forage(policy: ForagingPolicy) -> ForagingResult {
+        Experiment::from_toml(SPEC).unwrap().forage(&Foraging {
+            trips: 2,
+            route: TripRoute::Random,
+            arrival_radius: 10.0,
+            recalibration: Recalibration::Keep,
+            policy,
+            record_flights: true,
+        })
+    }
+
+    #[test]
+    fn trips_continue_where_the_last_ended() {
+        let result = forage(ForagingPolicy::Continue);
+        let flights = result.flights.unwrap();
+        assert!(!result.trips[0].arrived);
+        assert!(!result.trips[1].reset_to_nest);
+        let end = flights[0].physical_states.last().unwrap().position;
+        assert_eq!(result.trips[1].start, end);
+        assert_eq!(flights[1].dropped, flights[0].step_count());
+
+        // Probes sample the kept states only, and not the one the trip continues from
+        let probes = flights[1].probe_record.as_ref().unwrap();
+        assert_eq!(probes.steps.len(), flights[1].physical_states.len() - 1);
+        assert!(probes
+            .steps
+            .iter()
+            .all(|&step| step < flights[1].physical_states.len()));
+    }
+
+    #[test]
+    fn trips_can_reset_to_nest() {
+        let result = forage(ForagingPolicy::ResetToNest);
+        let flights = result.flights.unwrap();
+        assert!(result.trips[1].reset_to_nest);
+        assert_eq!(result.trips[1].start, Vector2::zeros());
+        assert_eq!(flights[1].dropped, 0);
+    }
+}
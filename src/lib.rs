@@ -1,4 +1,4 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
 
 use model::{
@@ -6,24 +6,46 @@ use model::{
     constants::{AMP_BIAS_TUNED, AMP_SLOPE_TUNED, N_AMP, N_CPU4, N_PONTINE},
     memory::{
         self,
-        reference::AbstractMemoryRecorder,
-        weights::{AffineDynamics, Dynamics, LogisticDynamics, PontineWeightMemoryRecorder},
-        MemoryRecorder,
+        reference::{AbstractMemoryRecorder, AbstractRecalibration, AbstractVectorMemory},
+        weights::{
+            AffineDynamics, Dynamics, LogisticDynamics, PontineWeightMemoryRecorder,
+            WeightRecalibration, WeightVectorMemory,
+        },
     },
     network::{PassthroughLayer, SigmoidLayer, StaticWeights, WeightMatrix},
-    Config, CX,
+    Config, Senses, CX,
 };
-use movement::{PhysicalState, DEFAULT_DRAG};
-use nalgebra::SVector;
+use movement::{Altitude, Displacement, PhysicalState, TimeStep, Wind};
+use nalgebra::{SVector, Vector2};
+use outbound::Outbound;
+use probe::{ProbeRecord, Probes};
+use protocol::{run_protocol_with, Control, PhaseLabel, StopConditions};
+use sink::Sink;
 use util::Random;
+use vision::Vision;
+use world::World;
 
+pub mod batch;
+pub mod checkpoint;
+pub mod decode;
+pub mod experiment;
+pub mod foraging;
 pub mod model;
 pub mod movement;
+pub mod outbound;
+pub mod probe;
+pub mod protocol;
+pub mod sink;
+pub mod sky;
 pub mod stats;
+pub mod track;
 pub mod util;
+pub mod vision;
+pub mod world;
 
 pub const COMMON_SEED: Option<u64> = Some(64172527321326);
 
+#[derive(Clone)]
 pub struct ReferenceConfig;
 impl model::Config for ReferenceConfig {
     type Cpu4Layer = memory::reference::AbstractCpu4;
@@ -31,19 +53,24 @@ impl model::Config for ReferenceConfig {
     type Cpu4AmpWeights = StaticWeights<N_AMP, N_CPU4>;
     type Cpu4PontineWeights = StaticWeights<N_PONTINE, N_CPU4>;
     type MemoryRecorder = AbstractMemoryRecorder;
+    type VectorMemory = AbstractVectorMemory;
+    type Recalibration = AbstractRecalibration;
 }
 
-pub fn create_reference_cx<'a>(random: &'a Random) -> CX<'a, ReferenceConfig> {
+pub fn create_reference_cx(mut random: Random) -> CX<ReferenceConfig> {
+    let w_cpu4_amp = StaticWeights::noisy(&mut random, &W_CPU4_AMP);
+    let w_cpu4_pontine = StaticWeights::noisy(&mut random, &W_CPU4_PONTINE);
     CX::new(
         random,
         0.25,
         memory::reference::AbstractCpu4::new(),
         PassthroughLayer,
-        StaticWeights::noisy(random, &W_CPU4_AMP),
-        StaticWeights::noisy(random, &W_CPU4_PONTINE),
+        w_cpu4_amp,
+        w_cpu4_pontine,
     )
 }
 
+#[derive(Clone)]
 pub struct WeightConfig<D: Dynamics>(PhantomData<D>);
 impl<D: Dynamics> model::Config for WeightConfig<D> {
     type Cpu4Layer = memory::weights::StatelessCpu4;
@@ -51,8 +78,11 @@ impl<D: Dynamics> model::Config for WeightConfig<D> {
     type Cpu4AmpWeights = memory::weights::DynamicWeights<D, N_AMP, N_CPU4>;
     type Cpu4PontineWeights = memory::weights::DynamicWeights<D, N_PONTINE, N_CPU4>;
     type MemoryRecorder = PontineWeightMemoryRecorder;
+    type VectorMemory = WeightVectorMemory;
+    type Recalibration = WeightRecalibration;
 }
 
+#[derive(Clone)]
 pub struct WeightAmpConfig<D: Dynamics>(PhantomData<D>);
 impl<D: Dynamics> model::Config for WeightAmpConfig<D> {
     type Cpu4Layer = memory::weights::StatelessCpu4;
@@ -60,15 +90,17 @@ impl<D: Dynamics> model::Config for WeightAmpConfig<D> {
     type Cpu4AmpWeights = memory::weights::DynamicWeights<D, N_AMP, N_CPU4>;
     type Cpu4PontineWeights = memory::weights::DynamicWeights<D, N_PONTINE, N_CPU4>;
     type MemoryRecorder = PontineWeightMemoryRecorder;
+    type VectorMemory = WeightVectorMemory;
+    type Recalibration = WeightRecalibration;
 }
 
-pub fn create_weight_cx<'a, D: Dynamics>(
-    random: &'a Random,
+pub fn create_weight_cx<D: Dynamics>(
+    random: Random,
     dynamics: &D,
     beta: f32,
     initial_weight: f32,
     turn_sharpness: f32,
-) -> CX<'a, WeightConfig<D>> {
+) -> CX<WeightConfig<D>> {
     CX::new(
         random,
         turn_sharpness,
@@ -87,10 +119,7 @@ pub fn create_weight_cx<'a, D: Dynamics>(
     )
 }
 
-pub fn create_weight_affine_cx<'a>(
-    random: &'a Random,
-    beta: f32,
-) -> CX<'a, WeightConfig<AffineDynamics>> {
+pub fn create_weight_affine_cx(random: Random, beta: f32) -> CX<WeightConfig<AffineDynamics>> {
     let dynamics = AffineDynamics { beta };
     let initial_weight = 0.5;
     CX::new(
@@ -111,12 +140,12 @@ pub fn create_weight_affine_cx<'a>(
     )
 }
 
-pub fn create_weight_logistic_cx<'a>(
-    random: &'a Random,
+pub fn create_weight_logistic_cx(
+    random: Random,
     h: f32,
     w0: f32,
     beta: f32,
-) -> CX<'a, WeightConfig<LogisticDynamics>> {
+) -> CX<WeightConfig<LogisticDynamics>> {
     let dynamics = LogisticDynamics { h };
     CX::new(
         random,
@@ -128,12 +157,12 @@ pub fn create_weight_logistic_cx<'a>(
     )
 }
 
-pub fn create_weight_logistic_amp_cx<'a>(
-    random: &'a Random,
+pub fn create_weight_logistic_amp_cx(
+    random: Random,
     h: f32,
     w0: f32,
     beta: f32,
-) -> CX<'a, WeightAmpConfig<LogisticDynamics>> {
+) -> CX<WeightAmpConfig<LogisticDynamics>> {
     let dynamics = LogisticDynamics { h };
     CX::new(
         random,
@@ -148,7 +177,7 @@ pub fn create_weight_logistic_amp_cx<'a>(
     )
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Setup {
     pub inbound_steps: usize,
     pub outbound_steps: usize,
@@ -156,26 +185,72 @@ pub struct Setup {
     pub acceleration_out: f32,
     pub acceleration_in: f32,
     pub record_memory: bool,
+    /// Displacement between outbound and homing, for release experiments.
+    #[serde(default)]
+    pub displacement: Option<Displacement>,
+    /// Conditions that end homing before `inbound_steps`.
+    #[serde(default)]
+    pub stop: StopConditions,
+    /// Neuron populations to record during the flight.
+    #[serde(default)]
+    pub probes: Probes,
+    #[serde(default)]
+    pub wind: Wind,
+    /// How the outbound route turns.
+    #[serde(default)]
+    pub outbound: Outbound,
+    #[serde(default)]
+    pub time_step: TimeStep,
+    #[serde(default)]
+    pub outbound_altitude: Altitude,
+    #[serde(default)]
+    pub inbound_altitude: Altitude,
+    #[serde(default)]
+    pub senses: Senses,
+    /// Obstacles in the way of the agent, and landmarks it can see.
+    #[serde(default)]
+    pub world: World,
+    /// Visual homing alongside path integration.
+    #[serde(default)]
+    pub vision: Option<Vision>,
 }
 
 impl Setup {
-    pub fn generate_outbound(&self, random: &Random) -> Vec<PhysicalState> {
+    pub fn generate_outbound(&self, random: &mut Random) -> Vec<PhysicalState> {
         // Generate an outbound path
-        let rng = &mut *random.rng();
-        movement::generate_outbound(
-            rng,
-            self.outbound_steps,
-            self.acceleration_out,
-            self.vary_speed,
-        )
+        let wind_seed = random.wind_seed();
+        movement::generate_outbound(random.route_rng(), wind_seed, self)
     }
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FlightData {
     pub setup: Setup,
+    /// The phases of the protocol that produced this flight.
+    pub phases: Vec<PhaseLabel>,
+    /// Index into `phases` of the phase that produced each physical state.
+    pub step_phases: Vec<usize>,
     pub physical_states: Vec<PhysicalState>,
-    pub memory_record: Option<Vec<SVector<f32, N_CPU4>>>,
+    /// Memory after the network has seen each physical state, if recorded in that phase.
+    pub memory_record: Option<Vec<Option<SVector<f32, N_CPU4>>>>,
+    /// Activities of the probed populations, if any.
+    #[serde(default)]
+    pub probe_record: Option<ProbeRecord>,
+    /// Positions where feeder vectors were stored, in order.
+    #[serde(default)]
+    pub feeders: Vec<Vector2<f32>>,
+    /// When streaming, the number of latest steps kept; all steps are kept otherwise.
+    #[serde(default)]
+    pub retain: Option<usize>,
+    /// Steps at the start of the flight that are no longer kept, or that were run in
+    /// the flight this one continues. Indices into `physical_states` and `step_phases`
+    /// count from the first step that is kept.
+    #[serde(default)]
+    pub dropped: usize,
+    /// Where the agent is before the first step: at rest at the nest, unless the flight
+    /// continues another one.
+    #[serde(default)]
+    pub initial: PhysicalState,
 }
 
 impl FlightData {
@@ -189,41 +264,16 @@ pub fn run_homing_trial<C: Config>(
     cx: &mut CX<C>,
     outbound: Vec<PhysicalState>,
 ) -> FlightData {
-    let mut physical_states = outbound;
-    let mut memory_record: Option<Vec<SVector<f32, N_CPU4>>> = if setup.record_memory {
-        Some(Vec::with_capacity(
-            setup.outbound_steps + setup.inbound_steps,
-        ))
-    } else {
-        None
-    };
-
-    // Simulate the agent flying along the outbound path
-    for state in
-        &physical_states[..physical_states.len()-1 /* leave last state for start of homing */]
-    {
-        cx.update(&state);
-
-        if let Some(ref mut memory_record) = memory_record {
-            memory_record.push(C::MemoryRecorder::record(&cx));
-        }
-    }
-
-    // Let the agent home, using the model's motor output to steer
-    physical_states.reserve(setup.inbound_steps);
-    for _ in 0..setup.inbound_steps {
-        let physical_state = physical_states.last().unwrap();
-        let motor = cx.update(physical_state);
-        physical_states.push(physical_state.next(motor, setup.acceleration_in, DEFAULT_DRAG));
-
-        if let Some(ref mut memory_record) = memory_record {
-            memory_record.push(C::MemoryRecorder::record(&cx));
-        }
-    }
+    run_homing_trial_with(setup, cx, outbound, &mut ())
+}
 
-    FlightData {
-        setup: setup.clone(),
-        physical_states,
-        memory_record,
-    }
+/// Like [`run_homing_trial`], passing every step to the sink as it happens.
+pub fn run_homing_trial_with<C: Config>(
+    setup: &Setup,
+    cx: &mut CX<C>,
+    outbound: Vec<PhysicalState>,
+    sink: &mut impl Sink,
+) -> FlightData {
+    let protocol = setup.protocol_with_outbound(Control::Replay { states: outbound });
+    run_protocol_with(cx, setup, &protocol, sink)
 }
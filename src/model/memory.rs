@@ -1,25 +1,33 @@
-use super::{constants::N_CPU4, Config, CX};
+use super::{constants::N_CPU4, Config, Steering, CX};
 use nalgebra::SVector;
 
 /// Reference implementation of memory acculumating in the CPU4 cells as in the Stone et al. (2017) paper.
 pub mod reference {
     use nalgebra::SVector;
+    use serde::{Deserialize, Serialize};
 
     use crate::{
         model::{
             constants::{self, CPU4_MEM_FADE, N_CPU4},
             network::{ActivityVector, Layer},
-            Config, CX,
+            Config, Steering, CX,
         },
         util::Random,
     };
 
-    use super::MemoryRecorder;
+    use super::{MemoryRecorder, Recalibration, VectorMemory};
 
+    #[derive(Clone, Serialize, Deserialize)]
     pub struct AbstractCpu4 {
         memory: ActivityVector<N_CPU4>,
     }
 
+    impl Default for AbstractCpu4 {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl AbstractCpu4 {
         pub fn new() -> AbstractCpu4 {
             AbstractCpu4 {
@@ -32,11 +40,12 @@ pub mod reference {
         fn update(
             &mut self,
             input: ActivityVector<N_CPU4>,
-            random: &Random,
+            dt: f32,
+            random: &mut Random,
         ) -> ActivityVector<N_CPU4> {
             let mem_update = input.map(|x| x.clamp(0.0, 1.0) - CPU4_MEM_FADE);
-            self.memory =
-                (&self.memory + mem_update * constants::CPU4_MEM_GAIN).map(|x| x.clamp(0.0, 1.0));
+            self.memory = (self.memory + mem_update * constants::CPU4_MEM_GAIN * dt)
+                .map(|x| x.clamp(0.0, 1.0));
 
             random.noisy_sigmoid(
                 &self.memory,
@@ -52,29 +61,66 @@ pub mod reference {
             cx.cpu4_layer.memory
         }
     }
+
+    /// Steers with CPU4 activity as if the memory were the current one minus the vector,
+    /// around the resting memory of 0.5.
+    pub struct AbstractVectorMemory;
+    impl<C: Config<Cpu4Layer = AbstractCpu4>> VectorMemory<C> for AbstractVectorMemory {
+        fn towards(cx: &mut CX<C>, vector: &SVector<f32, N_CPU4>, steering: Steering) -> Steering {
+            let memory = (cx.cpu4_layer.memory - vector).map(|x| (x + 0.5).clamp(0.0, 1.0));
+            Steering {
+                cpu4: cx.random_mut().noisy_sigmoid(
+                    &memory,
+                    constants::CPU4_SLOPE_TUNED,
+                    constants::CPU4_BIAS_TUNED,
+                ),
+                ..steering
+            }
+        }
+    }
+
+    pub struct AbstractRecalibration;
+    impl<C: Config<Cpu4Layer = AbstractCpu4>> Recalibration<C> for AbstractRecalibration {
+        fn recalibrate(cx: &mut CX<C>, rate: f32) {
+            let memory = &mut cx.cpu4_layer.memory;
+            let mean = memory.mean();
+            *memory += memory.map(|x| mean - x) * rate;
+        }
+    }
 }
 
 pub mod weights {
     use std::fmt::Debug;
 
     use nalgebra::SVector;
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
     use crate::{
         model::{
-            constants::{self, N_CPU4},
+            constants::{self, N_AMP, N_CPU4, N_PONTINE},
             network::{ActivityVector, Layer, WeightMatrix, Weights},
-            Config, CX,
+            Config, Steering, CX,
         },
         util::Random,
     };
 
-    use super::MemoryRecorder;
+    use super::{MemoryRecorder, Recalibration, VectorMemory};
 
-    pub trait Dynamics: Clone {
+    pub trait Dynamics: Clone + Send + Serialize + DeserializeOwned {
         fn dwdt(&self, w: f32, r: f32) -> f32;
+
+        /// The weight in a scale on which the input accumulates additively.
+        fn accumulated(&self, w: f32) -> f32 {
+            w
+        }
+
+        /// The weight at a point on the scale of [`Dynamics::accumulated`].
+        fn weight(&self, accumulated: f32) -> f32 {
+            accumulated
+        }
     }
 
-    #[derive(Clone)]
+    #[derive(Clone, Serialize, Deserialize)]
     pub struct AffineDynamics {
         pub beta: f32,
     }
@@ -86,7 +132,7 @@ pub mod weights {
         }
     }
 
-    #[derive(Clone)]
+    #[derive(Clone, Serialize, Deserialize)]
     pub struct LogisticDynamics {
         pub h: f32,
     }
@@ -94,32 +140,77 @@ pub mod weights {
         fn dwdt(&self, w: f32, r: f32) -> f32 {
             self.h * r * w * (1.0 - w)
         }
+
+        /// Log odds, which grow at `h * r`.
+        fn accumulated(&self, w: f32) -> f32 {
+            let w = w.clamp(f32::EPSILON, 1.0 - f32::EPSILON);
+            (w / (1.0 - w)).ln()
+        }
+
+        fn weight(&self, accumulated: f32) -> f32 {
+            1.0 / (1.0 + (-accumulated).exp())
+        }
     }
 
+    #[derive(Clone, Serialize, Deserialize)]
+    #[serde(bound = "")]
     pub struct DynamicWeights<D: Dynamics, const TO: usize, const FROM: usize> {
         dynamics: D,
-        connectivity: &'static WeightMatrix<TO, FROM>,
+        connectivity: WeightMatrix<TO, FROM>,
         weights: WeightMatrix<TO, FROM>,
     }
 
     impl<D: Dynamics, const TO: usize, const FROM: usize> DynamicWeights<D, TO, FROM> {
         pub fn new(
             dynamics: &D,
-            connectivity: &'static WeightMatrix<TO, FROM>,
+            connectivity: &WeightMatrix<TO, FROM>,
             initial: WeightMatrix<TO, FROM>,
         ) -> Self {
             Self {
                 dynamics: dynamics.clone(),
-                connectivity,
+                connectivity: *connectivity,
                 weights: initial.component_mul(connectivity),
             }
         }
+
+        /// The weights with what the synapses of each input had accumulated in `vector`,
+        /// beyond their mean, taken off again.
+        pub fn towards(&self, vector: &ActivityVector<FROM>) -> WeightMatrix<TO, FROM> {
+            let accumulated = vector.map(|w| self.dynamics.accumulated(w));
+            let shift = accumulated.add_scalar(-accumulated.mean());
+            let mut weights = self.weights;
+            for j in 0..FROM {
+                for i in 0..TO {
+                    let index = j * TO + i;
+                    let w = self.dynamics.accumulated(weights[index])
+                        - self.connectivity[index] * shift[j];
+                    weights[index] = self.dynamics.weight(w).clamp(0.0, 1.0);
+                }
+            }
+            weights
+        }
+
+        /// Moves every connected synapse part of the way to the mean of them all,
+        /// on the scale on which they accumulate input.
+        pub fn recalibrate(&mut self, rate: f32) {
+            let connected: Vec<usize> = (0..TO * FROM)
+                .filter(|&index| self.connectivity[index] != 0.0)
+                .collect();
+            let accumulated: Vec<f32> = connected
+                .iter()
+                .map(|&index| self.dynamics.accumulated(self.weights[index]))
+                .collect();
+            let mean = accumulated.iter().sum::<f32>() / accumulated.len().max(1) as f32;
+            for (&index, &w) in connected.iter().zip(&accumulated) {
+                self.weights[index] = self.dynamics.weight(w + (mean - w) * rate).clamp(0.0, 1.0);
+            }
+        }
     }
 
     impl<D: Dynamics, const TO: usize, const FROM: usize> Weights<TO, FROM>
         for DynamicWeights<D, TO, FROM>
     {
-        fn update(&mut self, input: &ActivityVector<FROM>) -> &WeightMatrix<TO, FROM> {
+        fn update(&mut self, input: &ActivityVector<FROM>, dt: f32) -> &WeightMatrix<TO, FROM> {
             // Each row in the weight matrix represents one synapse per input rate,
             // so each row gets element-wise multiplied with the connectivity and the current weights.
             //let signal = self.connectivity * WeightMatrix::from_diagonal(input);
@@ -133,8 +224,9 @@ pub mod weights {
                     let index = j * TO + i;
                     self.weights[index] = (self.weights[index]
                         + self.connectivity[index]
-                            * self.dynamics.dwdt(self.weights[index], input[j]))
-                    .clamp(0.0, 1.0);
+                            * self.dynamics.dwdt(self.weights[index], input[j])
+                            * dt)
+                        .clamp(0.0, 1.0);
                 }
             }
 
@@ -153,6 +245,7 @@ pub mod weights {
         }
     }
 
+    #[derive(Clone, Serialize, Deserialize)]
     pub struct StatelessCpu4 {
         beta: f32,
     }
@@ -167,7 +260,8 @@ pub mod weights {
         fn update(
             &mut self,
             input: ActivityVector<N_CPU4>,
-            random: &Random,
+            _dt: f32,
+            random: &mut Random,
         ) -> ActivityVector<N_CPU4> {
             random
                 .noisy_sigmoid(
@@ -185,8 +279,115 @@ pub mod weights {
             cx.w_cpu4_pontine.matrix().diagonal()
         }
     }
+
+    /// Steers with memory weights from which the vector's deviation from its mean is taken off,
+    /// so that they encode the way from where the vector was stored. The vector is read
+    /// from the pontine weights, which see the same input as the amp weights.
+    pub struct WeightVectorMemory;
+    impl<D, C> VectorMemory<C> for WeightVectorMemory
+    where
+        D: Dynamics,
+        C: Config<
+            Cpu4PontineWeights = DynamicWeights<D, N_PONTINE, N_CPU4>,
+            Cpu4AmpWeights = DynamicWeights<D, N_AMP, N_CPU4>,
+        >,
+    {
+        fn towards(cx: &mut CX<C>, vector: &SVector<f32, N_CPU4>, steering: Steering) -> Steering {
+            Steering {
+                w_cpu4_pontine: cx.w_cpu4_pontine.towards(vector),
+                w_cpu4_amp: cx.w_cpu4_amp.towards(vector),
+                ..steering
+            }
+        }
+    }
+
+    pub struct WeightRecalibration;
+    impl<D, C> Recalibration<C> for WeightRecalibration
+    where
+        D: Dynamics,
+        C: Config<
+            Cpu4PontineWeights = DynamicWeights<D, N_PONTINE, N_CPU4>,
+            Cpu4AmpWeights = DynamicWeights<D, N_AMP, N_CPU4>,
+        >,
+    {
+        fn recalibrate(cx: &mut CX<C>, rate: f32) {
+            cx.w_cpu4_pontine.recalibrate(rate);
+            cx.w_cpu4_amp.recalibrate(rate);
+        }
+    }
 }
 
 pub trait MemoryRecorder<C: Config> {
     fn record(cx: &CX<C>) -> SVector<f32, N_CPU4>;
 }
+
+/// Steering to a place remembered as a snapshot of the path integration memory,
+/// as in Le Moël et al. (2019): the steering stage compares the current memory
+/// against the snapshot instead of reading it as the way home.
+pub trait VectorMemory<C: Config> {
+    /// Steering inputs for heading to where `vector` was stored, given those for heading home.
+    fn towards(cx: &mut CX<C>, vector: &SVector<f32, N_CPU4>, steering: Steering) -> Steering;
+}
+
+/// Clearing the home vector from the memory, as when the agent recognizes the nest.
+pub trait Recalibration<C: Config> {
+    /// Moves the memory of every cell a `rate` of the way to the mean over all cells,
+    /// so that a rate of 1 leaves no home vector while keeping the overall level.
+    fn recalibrate(cx: &mut CX<C>, rate: f32);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{experiment::Experiment, protocol::StopReason, FlightData};
+
+    /// Out to a feeder, home, and back to the feeder from its stored vector.
+    fn revisit(model: &str, seed: u64) -> FlightData {
+        let spec = format!(
+            r#"
+            seed = {seed}
+
+            [setup]
+            outbound_steps = 1000
+            inbound_steps = 1500
+            acceleration_out = 0.15
+            acceleration_in = 0.1
+            vary_speed = true
+            record_memory = false
+            stop = {{ radius = 10.0 }}
+
+            [model]
+            {model}
+            "#
+        );
+        let mut experiment = Experiment::from_toml(&spec).unwrap();
+        experiment.protocol = Some(experiment.setup.with_revisit(experiment.protocol()));
+        experiment.run()
+    }
+
+    fn closest_to_feeder(flight: &FlightData) -> f32 {
+        flight
+            .steps_in("revisit")
+            .map(|step| (flight.physical_states[step].position - flight.feeders[0]).magnitude())
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    #[test]
+    fn agents_travel_back_to_a_remembered_feeder() {
+        for seed in 1..=3 {
+            let flight = revisit(r#"variant = "reference""#, seed);
+            let phase = flight.phases.last().unwrap();
+            assert_eq!(phase.name, "revisit");
+            assert_eq!(phase.termination.unwrap().reason, StopReason::ReachedFeeder);
+            assert!(closest_to_feeder(&flight) <= 10.0);
+
+            let flight = revisit(
+                r#"variant = "logistic"
+                h = 0.0048329304
+                w0 = 6.1584935e-5
+                beta = 0.6631579"#,
+                seed,
+            );
+            assert!(closest_to_feeder(&flight) < 0.5 * flight.feeders[0].magnitude());
+        }
+    }
+}
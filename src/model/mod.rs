@@ -5,27 +5,213 @@ pub mod constants;
 pub mod memory;
 pub mod network;
 
+use std::fmt;
+
 use nalgebra::{matrix, SVector, Vector2};
 use ndarray::{prelude::*, Axis};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
     movement::PhysicalState,
+    sky::Skylight,
     util::{self, Random},
+    vision::VisualMemory,
 };
 use constants::{N_CL1, N_CPU1A, N_CPU1B, N_CPU4, N_PONTINE, N_TB1, N_TL2, N_TN1, N_TN2};
 use network::*;
 
-use self::{constants::N_AMP, memory::MemoryRecorder};
+use self::{
+    constants::N_AMP,
+    memory::{MemoryRecorder, Recalibration, VectorMemory},
+};
 
 pub trait Config: Sized {
-    type Cpu4Layer: Layer<N_CPU4>;
-    type Cpu4AmpWeights: Weights<N_AMP, N_CPU4>;
-    type Cpu4PontineWeights: Weights<N_PONTINE, N_CPU4>;
-    type AmpLayer: Layer<N_AMP>;
+    type Cpu4Layer: Layer<N_CPU4> + Clone + Send + Serialize + DeserializeOwned;
+    type Cpu4AmpWeights: Weights<N_AMP, N_CPU4> + Clone + Send + Serialize + DeserializeOwned;
+    type Cpu4PontineWeights: Weights<N_PONTINE, N_CPU4>
+        + Clone
+        + Send
+        + Serialize
+        + DeserializeOwned;
+    type AmpLayer: Layer<N_AMP> + Clone + Send + Serialize + DeserializeOwned;
     type MemoryRecorder: MemoryRecorder<Self>;
+    type VectorMemory: VectorMemory<Self>;
+    type Recalibration: Recalibration<Self>;
+}
+
+/// Where the steering stage leads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Goal {
+    #[default]
+    Home,
+    /// Where the vector memory was stored, or home if there is none.
+    Vector,
+}
+
+/// The path integration memory as the steering stage reads it: CPU4 activity,
+/// and the weights from CPU4 to the pontine and amp cells.
+#[derive(Clone, Debug)]
+pub struct Steering {
+    pub cpu4: ActivityVector<N_CPU4>,
+    pub w_cpu4_pontine: WeightMatrix<N_PONTINE, N_CPU4>,
+    pub w_cpu4_amp: WeightMatrix<N_AMP, N_CPU4>,
+}
+
+/// How the agent's senses respond to its physical state.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Senses {
+    #[serde(default)]
+    pub optic_flow: OpticFlow,
+    #[serde(default)]
+    pub compass: Compass,
+}
+
+/// What the TN cells' optic flow input depends on.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OpticFlow {
+    /// Ground speed alone, as if always at the same height.
+    #[default]
+    Planar,
+    Ventral(VentralFlow),
+}
+
+/// Ventral flow, scaling inversely with altitude so that it matches planar flow
+/// at `reference_altitude`. Altitudes below `min_altitude` are taken as `min_altitude`,
+/// so that the flow stays finite on the ground.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "VentralFlowSpec")]
+pub struct VentralFlow {
+    reference_altitude: f32,
+    min_altitude: f32,
+}
+
+/// Ventral flow as given in a spec, before it is checked.
+#[derive(Deserialize)]
+struct VentralFlowSpec {
+    reference_altitude: f32,
+    #[serde(default = "default_min_altitude")]
+    min_altitude: f32,
+}
+
+fn default_min_altitude() -> f32 {
+    0.1
+}
+
+#[derive(Debug)]
+pub struct InvalidVentralFlow {
+    pub reference_altitude: f32,
+    pub min_altitude: f32,
+}
+
+impl fmt::Display for InvalidVentralFlow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ventral flow needs finite, positive reference and minimum altitudes, got {} and {}",
+            self.reference_altitude, self.min_altitude
+        )
+    }
 }
 
-pub struct CX<'a, C: Config> {
+impl std::error::Error for InvalidVentralFlow {}
+
+impl TryFrom<VentralFlowSpec> for VentralFlow {
+    type Error = InvalidVentralFlow;
+
+    fn try_from(spec: VentralFlowSpec) -> Result<Self, Self::Error> {
+        VentralFlow::new(spec.reference_altitude, spec.min_altitude)
+    }
+}
+
+impl VentralFlow {
+    pub fn new(
+        reference_altitude: f32,
+        min_altitude: f32,
+    ) -> Result<VentralFlow, InvalidVentralFlow> {
+        let valid = |altitude: f32| altitude.is_finite() && altitude > 0.0;
+        if valid(reference_altitude) && valid(min_altitude) {
+            Ok(VentralFlow {
+                reference_altitude,
+                min_altitude,
+            })
+        } else {
+            Err(InvalidVentralFlow {
+                reference_altitude,
+                min_altitude,
+            })
+        }
+    }
+
+    pub fn reference_altitude(&self) -> f32 {
+        self.reference_altitude
+    }
+
+    pub fn min_altitude(&self) -> f32 {
+        self.min_altitude
+    }
+}
+
+impl OpticFlow {
+    /// Velocity as the optic flow makes it appear.
+    pub fn apparent_velocity(&self, physical_state: &PhysicalState) -> Vector2<f32> {
+        // Optic flow follows the movement over the ground, including any drift in the wind
+        let velocity = physical_state.ground_velocity();
+        match *self {
+            OpticFlow::Planar => velocity,
+            OpticFlow::Ventral(ventral) => {
+                velocity * ventral.reference_altitude
+                    / physical_state.altitude.max(ventral.min_altitude)
+            }
+        }
+    }
+}
+
+/// Where the TL2 cells' heading input comes from.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Compass {
+    /// The true heading.
+    #[default]
+    Heading,
+    /// The heading as polarization-sensitive units read it from the sky.
+    Skylight(Skylight),
+}
+
+impl Compass {
+    /// The heading as sensed at `time` since the start of the flight,
+    /// or `None` if nothing is sensed, e.g. under full cloud cover.
+    pub fn heading(&self, heading: f32, time: f32, rng: &mut impl Rng) -> Option<f32> {
+        match *self {
+            Compass::Heading => Some(heading),
+            Compass::Skylight(ref skylight) => skylight.heading(heading, time, rng),
+        }
+    }
+}
+
+/// Population activities of the most recent update.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Activity {
+    pub tl2: ActivityVector<N_TL2>,
+    pub cl1: ActivityVector<N_CL1>,
+    pub tb1: ActivityVector<N_TB1>,
+    pub tn1: ActivityVector<N_TN1>,
+    pub tn2: ActivityVector<N_TN2>,
+    pub cpu4: ActivityVector<N_CPU4>,
+    pub pontine: ActivityVector<N_PONTINE>,
+    pub amp: ActivityVector<N_AMP>,
+    pub cpu1a: ActivityVector<N_CPU1A>,
+    pub cpu1b: ActivityVector<N_CPU1B>,
+    pub motor: f32,
+}
+
+/// The network owns its random state, so it can be cloned mid-flight and moved across threads,
+/// and serialized as a whole.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct CX<C: Config> {
     pub w_cl1_tb1: StaticWeights<N_TB1, N_CL1>,
     pub w_tb1_tb1: StaticWeights<N_TB1, N_TB1>,
 
@@ -51,16 +237,24 @@ pub struct CX<'a, C: Config> {
     pub cpu4_layer: C::Cpu4Layer,
     pub amp_layer: C::AmpLayer,
 
-    turn_sharpness: f32,
+    pub turn_sharpness: f32,
 
     tn_prefs: f32,
     tl2_prefs: SVector<f32, N_TL2>,
-    random: &'a Random,
+    random: Random,
+    #[serde(default)]
+    activity: Activity,
+    /// Views learned so far, if the agent homes visually as well.
+    #[serde(default)]
+    pub visual_memory: Option<VisualMemory>,
+    /// Path integration memory stored at a feeder, for travelling back to it.
+    #[serde(default)]
+    pub vector_memory: Option<SVector<f32, N_CPU4>>,
 }
 
-impl<'a, C: Config> CX<'a, C> {
+impl<C: Config> CX<C> {
     pub fn new(
-        random: &'a Random,
+        mut random: Random,
         turn_sharpness: f32,
         cpu4: C::Cpu4Layer,
         amp: C::AmpLayer,
@@ -68,26 +262,26 @@ impl<'a, C: Config> CX<'a, C> {
         w_cpu4_pontine: C::Cpu4PontineWeights,
     ) -> Self {
         CX {
-            w_cl1_tb1: StaticWeights::noisy(random, &connectomics::W_CL1_TB1),
+            w_cl1_tb1: StaticWeights::noisy(&mut random, &connectomics::W_CL1_TB1),
 
-            w_tb1_tb1: StaticWeights::noisy(random, &connectomics::generate_tb_tb_weights()),
-            w_tb1_cpu1a: StaticWeights::noisy(random, &connectomics::W_TB1_CPU1A),
-            w_tb1_cpu1b: StaticWeights::noisy(random, &connectomics::W_TB1_CPU1B),
-            w_tb1_cpu4: StaticWeights::noisy(random, &connectomics::W_TB1_CPU4),
+            w_tb1_tb1: StaticWeights::noisy(&mut random, &connectomics::generate_tb_tb_weights()),
+            w_tb1_cpu1a: StaticWeights::noisy(&mut random, &connectomics::W_TB1_CPU1A),
+            w_tb1_cpu1b: StaticWeights::noisy(&mut random, &connectomics::W_TB1_CPU1B),
+            w_tb1_cpu4: StaticWeights::noisy(&mut random, &connectomics::W_TB1_CPU4),
 
-            w_tn1_cpu4: StaticWeights::noisy(random, &connectomics::W_TN1_CPU4),
-            w_tn2_cpu4: StaticWeights::noisy(random, &connectomics::W_TN2_CPU4),
+            w_tn1_cpu4: StaticWeights::noisy(&mut random, &connectomics::W_TN1_CPU4),
+            w_tn2_cpu4: StaticWeights::noisy(&mut random, &connectomics::W_TN2_CPU4),
 
             w_cpu4_amp,
             w_cpu4_pontine,
 
-            w_pontine_amp: StaticWeights::noisy(random, &connectomics::W_PONTINE_AMP),
+            w_pontine_amp: StaticWeights::noisy(&mut random, &connectomics::W_PONTINE_AMP),
 
-            w_amp_cpu1a: StaticWeights::noisy(random, &connectomics::W_AMP_CPU1A),
-            w_amp_cpu1b: StaticWeights::noisy(random, &connectomics::W_AMP_CPU1B),
+            w_amp_cpu1a: StaticWeights::noisy(&mut random, &connectomics::W_AMP_CPU1A),
+            w_amp_cpu1b: StaticWeights::noisy(&mut random, &connectomics::W_AMP_CPU1B),
 
-            w_cpu1a_motor: StaticWeights::noisy(random, &connectomics::W_CPU1A_MOTOR),
-            w_cpu1b_motor: StaticWeights::noisy(random, &connectomics::W_CPU1B_MOTOR),
+            w_cpu1a_motor: StaticWeights::noisy(&mut random, &connectomics::W_CPU1A_MOTOR),
+            w_cpu1b_motor: StaticWeights::noisy(&mut random, &connectomics::W_CPU1B_MOTOR),
 
             tb1: ActivityVector::zeros(),
             cpu4_layer: cpu4,
@@ -98,16 +292,44 @@ impl<'a, C: Config> CX<'a, C> {
             tn_prefs: std::f32::consts::PI / 4.0,
             tl2_prefs: Self::generate_tl2_prefs(),
             random,
+            activity: Activity::default(),
+            visual_memory: None,
+            vector_memory: None,
         }
     }
 
-    pub fn update(&mut self, physical_state: &PhysicalState) -> f32 {
+    /// The network's random state, e.g. for drawing routes from its route stream.
+    pub fn random_mut(&mut self) -> &mut Random {
+        &mut self.random
+    }
+
+    /// Population activities of the most recent update.
+    pub fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    /// Stores the current path integration memory as the vector memory.
+    pub fn store_vector(&mut self) {
+        self.vector_memory = Some(C::MemoryRecorder::record(self));
+    }
+
+    /// Feeds a physical state to the network as the senses perceive it at `time`
+    /// since the start of the flight, integrating memory over `dt` time units,
+    /// and returns the motor output as a turning rate towards the goal.
+    pub fn update(
+        &mut self,
+        physical_state: &PhysicalState,
+        senses: &Senses,
+        goal: Goal,
+        time: f32,
+        dt: f32,
+    ) -> f32 {
         // Sensory inputs: heading
-        let tl2 = self.tl2_output(physical_state.heading);
+        let tl2 = self.tl2_output(physical_state.heading, &senses.compass, time);
         let cl1 = self.cl1_output(&tl2);
 
         // Sensory inputs: optical flow / speed
-        let flow = self.get_flow(physical_state);
+        let flow = self.get_flow(physical_state, &senses.optic_flow);
         let tn1 = self.tn1_output(&flow);
         let tn2 = self.tn2_output(&flow);
 
@@ -115,15 +337,38 @@ impl<'a, C: Config> CX<'a, C> {
         self.tb1 = self.tb1_output(&cl1);
 
         // Allocentric re-projection
-        let cpu4 = self.cpu4_update(&tn1, &tn2);
-
-        // Steering system
-        let pontine = self.pontine_output(&cpu4);
-        let amp = self.amp_output(&cpu4, &pontine);
-        let cpu1a = self.cpu1a_output(&amp);
-        let cpu1b = self.cpu1b_output(&amp);
-
-        self.turn_sharpness * self.motor_output(&cpu1a, &cpu1b)
+        let cpu4 = self.cpu4_update(&tn1, &tn2, dt);
+
+        // Steering system, reading the memory as the way home or comparing it to a stored vector
+        let mut steering = Steering {
+            cpu4,
+            w_cpu4_pontine: *self.w_cpu4_pontine.update(&cpu4, dt),
+            w_cpu4_amp: *self.w_cpu4_amp.update(&cpu4, dt),
+        };
+        if let (Goal::Vector, Some(vector)) = (goal, self.vector_memory) {
+            steering = C::VectorMemory::towards(self, &vector, steering);
+        }
+        let pontine = self.pontine_output(&steering);
+        let amp = self.amp_output(&steering, &pontine, dt);
+        let cpu1a = self.cpu1a_output(&amp, dt);
+        let cpu1b = self.cpu1b_output(&amp, dt);
+
+        let motor = self.turn_sharpness * self.motor_output(&cpu1a, &cpu1b);
+
+        self.activity = Activity {
+            tl2,
+            cl1,
+            tb1: self.tb1,
+            tn1,
+            tn2,
+            cpu4,
+            pontine,
+            amp,
+            cpu1a,
+            cpu1b,
+            motor,
+        };
+        motor
     }
 
     fn generate_tl2_prefs() -> SVector<f32, N_TL2> {
@@ -136,19 +381,24 @@ impl<'a, C: Config> CX<'a, C> {
         SVector::<f32, N_TL2>::from_vec(tl2_prefs.into_raw_vec())
     }
 
-    fn get_flow(&self, PhysicalState { heading, velocity }: &PhysicalState) -> Vector2<f32> {
+    fn get_flow(&self, physical_state: &PhysicalState, optic_flow: &OpticFlow) -> Vector2<f32> {
         // TODO: figure out what this is supposed to be (currently opposite Stone?)
+        let heading = physical_state.heading;
         let right = heading - self.tn_prefs;
         let left = heading + self.tn_prefs;
         let sensitivity = matrix![
             right.sin(), right.cos();
             left.sin(), left.cos();
         ];
-        sensitivity * velocity
+        sensitivity * optic_flow.apparent_velocity(physical_state)
     }
 
-    fn tl2_output(&self, heading: f32) -> ActivityVector<N_TL2> {
-        let input = self.tl2_prefs.map(|pref| (heading - pref).cos());
+    fn tl2_output(&mut self, heading: f32, compass: &Compass, time: f32) -> ActivityVector<N_TL2> {
+        // Without a sensed heading, the TL2 cells get no directional input
+        let input = match compass.heading(heading, time, self.random.senses_rng()) {
+            Some(heading) => self.tl2_prefs.map(|pref| (heading - pref).cos()),
+            None => ActivityVector::zeros(),
+        };
         self.random.noisy_sigmoid(
             &input,
             constants::TL2_SLOPE_TUNED,
@@ -156,7 +406,7 @@ impl<'a, C: Config> CX<'a, C> {
         )
     }
 
-    fn cl1_output(&self, tl2: &ActivityVector<N_TL2>) -> ActivityVector<N_CL1> {
+    fn cl1_output(&mut self, tl2: &ActivityVector<N_TL2>) -> ActivityVector<N_CL1> {
         let input = -tl2;
         self.random.noisy_sigmoid(
             &input,
@@ -165,7 +415,7 @@ impl<'a, C: Config> CX<'a, C> {
         )
     }
 
-    fn tb1_output(&self, cl1: &ActivityVector<N_CL1>) -> ActivityVector<N_TB1> {
+    fn tb1_output(&mut self, cl1: &ActivityVector<N_CL1>) -> ActivityVector<N_TB1> {
         let prop_cl1 = 0.667f32;
         let prop_tb1 = 1.0 - prop_cl1;
 
@@ -179,12 +429,12 @@ impl<'a, C: Config> CX<'a, C> {
         )
     }
 
-    fn tn1_output(&self, flow: &Vector2<f32>) -> ActivityVector<N_TN2> {
+    fn tn1_output(&mut self, flow: &Vector2<f32>) -> ActivityVector<N_TN2> {
         self.random
             .noisify_activity(&(flow.map(|x| (1.0 - x) / 2.0)))
     }
 
-    fn tn2_output(&self, flow: &Vector2<f32>) -> ActivityVector<N_TN2> {
+    fn tn2_output(&mut self, flow: &Vector2<f32>) -> ActivityVector<N_TN2> {
         self.random.noisify_activity(flow)
     }
 
@@ -192,14 +442,15 @@ impl<'a, C: Config> CX<'a, C> {
         &mut self,
         _tn1: &ActivityVector<N_TN1>,
         tn2: &ActivityVector<N_TN2>,
+        dt: f32,
     ) -> ActivityVector<N_CPU4> {
         let input = self.w_tn2_cpu4.matrix() * tn2 - self.w_tb1_cpu4.matrix() * self.tb1;
 
-        self.cpu4_layer.update(input, &self.random)
+        self.cpu4_layer.update(input, dt, &mut self.random)
     }
 
-    fn pontine_output(&mut self, cpu4: &ActivityVector<N_CPU4>) -> ActivityVector<N_PONTINE> {
-        let input = self.w_cpu4_pontine.update(&cpu4) * cpu4;
+    fn pontine_output(&mut self, steering: &Steering) -> ActivityVector<N_PONTINE> {
+        let input = steering.w_cpu4_pontine * steering.cpu4;
 
         // The activation function has been changed from a sigmoid
         // that is approximately linear in [0, 1] to a rectified linear curve
@@ -216,17 +467,18 @@ impl<'a, C: Config> CX<'a, C> {
 
     fn amp_output(
         &mut self,
-        cpu4: &ActivityVector<N_CPU4>,
+        steering: &Steering,
         pontine: &ActivityVector<N_PONTINE>,
+        dt: f32,
     ) -> ActivityVector<N_AMP> {
-        let input = 0.5 * self.w_cpu4_amp.update(&cpu4) * cpu4
-            - 0.5 * self.w_pontine_amp.matrix() * pontine;
+        let input =
+            0.5 * steering.w_cpu4_amp * steering.cpu4 - 0.5 * self.w_pontine_amp.matrix() * pontine;
 
-        self.amp_layer.update(input, &self.random)
+        self.amp_layer.update(input, dt, &mut self.random)
     }
 
-    fn cpu1a_output(&mut self, amp: &ActivityVector<N_AMP>) -> ActivityVector<N_CPU1A> {
-        let input = self.w_amp_cpu1a.update(&amp) * amp - self.w_tb1_cpu1a.matrix() * self.tb1;
+    fn cpu1a_output(&mut self, amp: &ActivityVector<N_AMP>, dt: f32) -> ActivityVector<N_CPU1A> {
+        let input = self.w_amp_cpu1a.update(amp, dt) * amp - self.w_tb1_cpu1a.matrix() * self.tb1;
 
         self.random.noisy_sigmoid(
             &input,
@@ -235,8 +487,8 @@ impl<'a, C: Config> CX<'a, C> {
         )
     }
 
-    fn cpu1b_output(&mut self, amp: &ActivityVector<N_AMP>) -> ActivityVector<N_CPU1B> {
-        let input = self.w_amp_cpu1b.update(&amp) * amp - self.w_tb1_cpu1b.matrix() * self.tb1;
+    fn cpu1b_output(&mut self, amp: &ActivityVector<N_AMP>, dt: f32) -> ActivityVector<N_CPU1B> {
+        let input = self.w_amp_cpu1b.update(amp, dt) * amp - self.w_tb1_cpu1b.matrix() * self.tb1;
 
         self.random.noisy_sigmoid(
             &input,
@@ -254,3 +506,64 @@ impl<'a, C: Config> CX<'a, C> {
         motor[0] - motor[1]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode::HomeVectorDecoding, experiment::Experiment};
+
+    /// Length of the home vector in memory after an outbound route at `height`.
+    fn remembered_distance(optic_flow: &str, height: f32) -> f32 {
+        let spec = format!(
+            r#"
+            seed = 3
+
+            [setup]
+            outbound_steps = 500
+            inbound_steps = 0
+            acceleration_out = 0.15
+            acceleration_in = 0.1
+            vary_speed = true
+            record_memory = true
+            outbound_altitude = {{ type = "constant", height = {height} }}
+            senses = {{ optic_flow = {optic_flow} }}
+
+            [model]
+            variant = "reference"
+            "#
+        );
+        let flight = Experiment::from_toml(&spec).unwrap().run();
+        let decoding = HomeVectorDecoding::analyze(&flight, Some(1.0)).unwrap();
+        decoding.steps.last().unwrap().decoded.magnitude()
+    }
+
+    #[test]
+    fn flying_higher_than_the_reference_shortens_the_remembered_distance() {
+        let ventral = r#"{ type = "ventral", reference_altitude = 10.0 }"#;
+        let planar = remembered_distance(r#"{ type = "planar" }"#, 10.0);
+        assert_eq!(remembered_distance(ventral, 10.0), planar);
+        // Not quite in proportion, since the memory is not linear in the flow
+        let ratio = remembered_distance(ventral, 20.0) / planar;
+        assert!(0.4 < ratio && ratio < 0.6);
+        let ratio = remembered_distance(ventral, 5.0) / planar;
+        assert!(1.8 < ratio && ratio < 2.4);
+    }
+
+    #[test]
+    fn ventral_flow_stays_finite_on_the_ground() {
+        let ventral = OpticFlow::Ventral(VentralFlow::new(10.0, 0.5).unwrap());
+        let state = PhysicalState {
+            velocity: Vector2::new(0.0, 1.0),
+            altitude: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(ventral.apparent_velocity(&state), Vector2::new(0.0, 20.0));
+
+        assert!(VentralFlow::new(10.0, 0.0).is_err());
+        assert!(VentralFlow::new(0.0, 0.1).is_err());
+        assert!(VentralFlow::new(f32::NAN, 0.1).is_err());
+        let flow = |spec: &str| toml::from_str::<OpticFlow>(spec);
+        assert!(flow("type = \"ventral\"\nreference_altitude = 10.0").is_ok());
+        assert!(flow("type = \"ventral\"\nreference_altitude = 10.0\nmin_altitude = 0.0").is_err());
+    }
+}
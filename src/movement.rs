@@ -1,62 +1,442 @@
+use std::fmt;
+
 use nalgebra::{DVector, SVector, Vector2};
 use rand::{distributions::Distribution, Rng};
 use rand_distr::Normal;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::{outbound::OutboundGenerator, util::derive_seed, world::World, Setup};
 
 pub const DEFAULT_ACCELERATION: f32 = 0.15;
 pub const DEFAULT_DRAG: f32 = 0.15;
 
-#[derive(Debug, Clone)]
+/// Where the agent is picked up in a release experiment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capture {
+    /// At the end of the outbound route, with a full home vector.
+    #[default]
+    Feeder,
+    /// After homing, with a (nearly) zero home vector.
+    Nest,
+}
+
+/// Moving the agent elsewhere while leaving its memory untouched, as in Wehner-style release experiments.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Displacement {
+    #[serde(default)]
+    pub capture: Capture,
+    #[serde(default = "Vector2::zeros")]
+    pub translation: Vector2<f32>,
+    /// Absolute release point, replacing `translation` when given.
+    #[serde(default)]
+    pub release_point: Option<Vector2<f32>>,
+    #[serde(default)]
+    pub rotation: f32,
+}
+
+impl Displacement {
+    pub fn apply(&self, state: &PhysicalState) -> PhysicalState {
+        let translation = match self.release_point {
+            Some(release_point) => release_point - state.position,
+            None => self.translation,
+        };
+        state.displaced(translation, self.rotation)
+    }
+}
+
+/// Simulated time per step. Each step updates the network once and integrates
+/// the physics `substeps` times, so movement can be resolved more finely than the network.
+///
+/// Speeds, accelerations, turns and memory changes are rates per unit time,
+/// so that with a unit step the simulation is the original discrete model.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "TimeStepSpec")]
+pub struct TimeStep {
+    dt: f32,
+    substeps: usize,
+}
+
+/// A time step as given in a spec, before it is checked.
+#[derive(Deserialize)]
+struct TimeStepSpec {
+    #[serde(default = "default_dt")]
+    dt: f32,
+    #[serde(default = "default_substeps")]
+    substeps: usize,
+}
+
+fn default_dt() -> f32 {
+    1.0
+}
+
+fn default_substeps() -> usize {
+    1
+}
+
+#[derive(Debug)]
+pub struct InvalidTimeStep {
+    pub dt: f32,
+    pub substeps: usize,
+}
+
+impl fmt::Display for InvalidTimeStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "time step must have a finite, positive dt and at least one substep, got dt {} with {} substeps",
+            self.dt, self.substeps
+        )
+    }
+}
+
+impl std::error::Error for InvalidTimeStep {}
+
+impl Default for TimeStep {
+    fn default() -> Self {
+        TimeStep {
+            dt: default_dt(),
+            substeps: default_substeps(),
+        }
+    }
+}
+
+impl TryFrom<TimeStepSpec> for TimeStep {
+    type Error = InvalidTimeStep;
+
+    fn try_from(spec: TimeStepSpec) -> Result<Self, Self::Error> {
+        TimeStep::new(spec.dt, spec.substeps)
+    }
+}
+
+impl TimeStep {
+    pub fn new(dt: f32, substeps: usize) -> Result<TimeStep, InvalidTimeStep> {
+        if dt.is_finite() && dt > 0.0 && substeps >= 1 {
+            Ok(TimeStep { dt, substeps })
+        } else {
+            Err(InvalidTimeStep { dt, substeps })
+        }
+    }
+
+    pub fn dt(&self) -> f32 {
+        self.dt
+    }
+
+    pub fn substeps(&self) -> usize {
+        self.substeps
+    }
+
+    /// Simulated time at the start of a step.
+    pub fn time(&self, step: usize) -> f32 {
+        step as f32 * self.dt
+    }
+}
+
+/// Air movement that carries the agent along with it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Wind {
+    #[default]
+    Calm,
+    Constant {
+        velocity: Vector2<f32>,
+    },
+    /// Gusts of up to `strength` on top of the mean wind, changing smoothly every `period` time units.
+    /// Without a `seed` every trial has its own gusts, drawn from the trial's seed.
+    Gusting {
+        mean: Vector2<f32>,
+        strength: f32,
+        period: f32,
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+    /// Varies linearly over space, as `base + gradient * position` with `gradient` given by rows.
+    Linear {
+        base: Vector2<f32>,
+        gradient: [[f32; 2]; 2],
+    },
+}
+
+impl Wind {
+    /// Wind velocity at a position and time, with `trial_seed` giving the gusts of an unseeded wind.
+    pub fn at(&self, position: &Vector2<f32>, time: f32, trial_seed: u64) -> Vector2<f32> {
+        match *self {
+            Wind::Calm => Vector2::zeros(),
+            Wind::Constant { velocity } => velocity,
+            Wind::Gusting {
+                mean,
+                strength,
+                period,
+                seed,
+            } => {
+                let time = time / period.max(f32::EPSILON);
+                let (key, offset) = (time.floor() as u64, time.fract());
+                let blend = offset * offset * (3.0 - 2.0 * offset);
+                let seed = seed.unwrap_or(trial_seed);
+                let (from, to) = (gust(seed, key), gust(seed, key + 1));
+                mean + (from + (to - from) * blend) * strength
+            }
+            Wind::Linear { base, gradient } => {
+                base + Vector2::new(
+                    gradient[0][0] * position.x + gradient[0][1] * position.y,
+                    gradient[1][0] * position.x + gradient[1][1] * position.y,
+                )
+            }
+        }
+    }
+}
+
+/// How high the agent flies during a phase, as a target altitude over time since the phase began.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Altitude {
+    /// Keeps whatever altitude the agent has, which is the ground for a new flight.
+    #[default]
+    Hold,
+    Constant {
+        height: f32,
+    },
+    /// From `start` to `end` at a constant rate over `duration` time units, then level.
+    Ramp {
+        start: f32,
+        end: f32,
+        duration: f32,
+    },
+    /// `mean + amplitude * sin(2 pi t / period)`.
+    Undulating {
+        mean: f32,
+        amplitude: f32,
+        period: f32,
+    },
+}
+
+impl Altitude {
+    /// Target altitude at time `time` into the phase, if there is one.
+    pub fn target(&self, time: f32) -> Option<f32> {
+        match *self {
+            Altitude::Hold => None,
+            Altitude::Constant { height } => Some(height),
+            Altitude::Ramp {
+                start,
+                end,
+                duration,
+            } => Some(start + (end - start) * (time / duration.max(f32::EPSILON)).min(1.0)),
+            Altitude::Undulating {
+                mean,
+                amplitude,
+                period,
+            } => Some(
+                mean + amplitude * (std::f32::consts::TAU * time / period.max(f32::EPSILON)).sin(),
+            ),
+        }
+    }
+
+    /// Climb rate that takes the agent to the target altitude by the end of a step of `dt`
+    /// starting at `time` into the phase.
+    pub fn climb_rate(&self, state: &PhysicalState, time: f32, dt: f32) -> f32 {
+        match self.target(time + dt) {
+            Some(target) => (target.max(0.0) - state.altitude) / dt,
+            None => 0.0,
+        }
+    }
+}
+
+/// A reproducible gust within the unit disk, so that gusting wind needs no random state.
+fn gust(seed: u64, key: u64) -> Vector2<f32> {
+    let hash = derive_seed(seed, &[key]);
+    let unit = |bits: u64| (bits & 0xFF_FFFF) as f32 / (1u64 << 24) as f32;
+    let angle = unit(hash) * std::f32::consts::TAU;
+    let magnitude = unit(hash >> 24).sqrt();
+    Vector2::new(angle.sin(), angle.cos()) * magnitude
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct PhysicalState {
+    pub position: Vector2<f32>,
+    /// Velocity relative to the air.
     pub velocity: Vector2<f32>,
     pub heading: f32,
+    /// Wind that carried the agent during the step to this state.
+    pub wind: Vector2<f32>,
+    /// Height above the ground, which only matters to ventral optic flow.
+    pub altitude: f32,
+    pub climb_rate: f32,
 }
 
+/// Serialized as `[vx, vy, heading, x, y, wind_x, wind_y, altitude, climb_rate]`;
+/// the wind and altitude may be left out when reading.
 impl Serialize for PhysicalState {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         use serde::ser::SerializeTuple;
-        let mut tuple = serializer.serialize_tuple(3)?;
+        let mut tuple = serializer.serialize_tuple(9)?;
         tuple.serialize_element(&self.velocity[0])?;
         tuple.serialize_element(&self.velocity[1])?;
         tuple.serialize_element(&self.heading)?;
+        tuple.serialize_element(&self.position[0])?;
+        tuple.serialize_element(&self.position[1])?;
+        tuple.serialize_element(&self.wind[0])?;
+        tuple.serialize_element(&self.wind[1])?;
+        tuple.serialize_element(&self.altitude)?;
+        tuple.serialize_element(&self.climb_rate)?;
         tuple.end()
     }
 }
 
+impl<'de> Deserialize<'de> for PhysicalState {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let values = Vec::<f32>::deserialize(deserializer)?;
+        if !matches!(values.len(), 5 | 7 | 9) {
+            return Err(serde::de::Error::invalid_length(
+                values.len(),
+                &"a physical state of 5, 7 or 9 values",
+            ));
+        }
+        let value = |i: usize| values.get(i).copied().unwrap_or(0.0);
+        Ok(PhysicalState {
+            position: Vector2::new(values[3], values[4]),
+            velocity: Vector2::new(values[0], values[1]),
+            heading: values[2],
+            wind: Vector2::new(value(5), value(6)),
+            altitude: value(7),
+            climb_rate: value(8),
+        })
+    }
+}
+
 impl PhysicalState {
     pub fn next(&self, rotation: f32, acceleration: f32, drag: f32) -> PhysicalState {
+        self.next_in_wind(rotation, acceleration, drag, Vector2::zeros())
+    }
+
+    /// Like [`PhysicalState::next`], with the agent also carried along by the wind.
+    pub fn next_in_wind(
+        &self,
+        rotation: f32,
+        acceleration: f32,
+        drag: f32,
+        wind: Vector2<f32>,
+    ) -> PhysicalState {
+        self.integrate(rotation, acceleration, drag, wind, 1.0)
+    }
+
+    /// Euler step over `dt` time units. `drag` is the fraction of velocity lost per unit time.
+    /// The agent keeps its climb rate, but not below the ground.
+    pub fn integrate(
+        &self,
+        rotation: f32,
+        acceleration: f32,
+        drag: f32,
+        wind: Vector2<f32>,
+        dt: f32,
+    ) -> PhysicalState {
+        let velocity = (self.velocity
+            + Vector2::new(self.heading.sin(), self.heading.cos()) * acceleration * dt)
+            * (1.0 - drag).powf(dt);
         PhysicalState {
-            velocity: (self.velocity
-                + Vector2::new(self.heading.sin(), self.heading.cos()) * acceleration)
-                * (1.0 - drag),
+            position: self.position + velocity * dt + wind * dt,
+            velocity,
+            heading: (self.heading + rotation * dt).rem_euclid(std::f32::consts::TAU),
+            wind,
+            altitude: (self.altitude + self.climb_rate * dt).max(0.0),
+            climb_rate: self.climb_rate,
+        }
+    }
+
+    /// The same state, climbing (or descending) at `climb_rate` from now on.
+    pub fn climbing(&self, climb_rate: f32) -> PhysicalState {
+        PhysicalState {
+            climb_rate,
+            ..self.clone()
+        }
+    }
+
+    /// One step of `time_step.dt`, integrated in `time_step.substeps` parts,
+    /// each of which collides with the world.
+    pub fn advance(
+        &self,
+        rotation: f32,
+        acceleration: f32,
+        drag: f32,
+        wind: Vector2<f32>,
+        time_step: &TimeStep,
+        world: &World,
+    ) -> PhysicalState {
+        let substeps = time_step.substeps;
+        let dt = time_step.dt / substeps as f32;
+        let mut state = self.clone();
+        for _ in 0..substeps {
+            let next = state.integrate(rotation, acceleration, drag, wind, dt);
+            state = world.collide(&state, next);
+        }
+        state
+    }
+
+    /// Velocity over the ground, as seen in the optic flow.
+    pub fn ground_velocity(&self) -> Vector2<f32> {
+        self.velocity + self.wind
+    }
+
+    /// The agent picked up, moved and rotated, and released at rest at the altitude
+    /// it was caught at, so that homing at a held altitude sees the same ventral flow.
+    pub fn displaced(&self, translation: Vector2<f32>, rotation: f32) -> PhysicalState {
+        PhysicalState {
+            position: self.position + translation,
+            velocity: Vector2::zeros(),
             heading: (self.heading + rotation).rem_euclid(std::f32::consts::TAU),
+            wind: Vector2::zeros(),
+            altitude: self.altitude,
+            climb_rate: 0.0,
         }
     }
 }
 
-pub fn generate_outbound(
-    rng: &mut impl Rng,
-    steps: usize,
-    acceleration: f32,
-    vary_speed: bool,
-) -> Vec<PhysicalState> {
-    let mut states = Vec::with_capacity(steps);
+/// The outbound route of a setup, in gusts from `wind_seed` unless the wind has its own.
+pub fn generate_outbound(rng: &mut impl Rng, wind_seed: u64, setup: &Setup) -> Vec<PhysicalState> {
+    let steps = setup.outbound_steps;
+    let dt = setup.time_step.dt;
 
     // Generate random motor outputs
-    let rotations = generate_rotations(rng, steps);
-    let accelerations = generate_accelerations(rng, steps, acceleration, vary_speed);
+    let mut generator = setup.outbound.generator(rng, steps, dt);
+    let accelerations =
+        generate_accelerations(rng, steps, setup.acceleration_out, setup.vary_speed, dt);
+
+    generate_route(
+        &mut generator,
+        &accelerations,
+        &setup.wind,
+        wind_seed,
+        &setup.outbound_altitude,
+        &setup.time_step,
+        &setup.world,
+    )
+}
+
+/// Runs a generator from the nest, one step per acceleration.
+pub fn generate_route(
+    generator: &mut impl OutboundGenerator,
+    accelerations: &DVector<f32>,
+    wind: &Wind,
+    wind_seed: u64,
+    altitude: &Altitude,
+    time_step: &TimeStep,
+    world: &World,
+) -> Vec<PhysicalState> {
+    let mut states = Vec::with_capacity(accelerations.len());
 
     // Step from initial physical state
-    let mut state = PhysicalState {
-        heading: 0.0,
-        velocity: Vector2::zeros(),
-    };
+    let mut state = PhysicalState::default();
 
-    for i in 0..steps {
-        state = state.next(rotations[i], accelerations[i], DEFAULT_DRAG);
+    for (i, &acceleration) in accelerations.iter().enumerate() {
+        let rotation = generator.turn(i, &state);
+        let gust = wind.at(&state.position, time_step.time(i), wind_seed);
+        state = state.climbing(altitude.climb_rate(&state, time_step.time(i), time_step.dt));
+        state = state.advance(rotation, acceleration, DEFAULT_DRAG, gust, time_step, world);
         states.push(state.clone());
     }
 
@@ -70,9 +450,7 @@ pub fn generate_rotations(rng: &mut impl Rng, steps: usize) -> DVector<f32> {
     let turns = DVector::from_iterator(steps, turn_distribution.sample_iter(rng).take(steps));
 
     let filter = SVector::<f32, 2>::repeat(1.0);
-    let turns = turns.convolve_same(filter / (filter.len() as f32));
-
-    turns
+    turns.convolve_same(filter / (filter.len() as f32))
 }
 
 pub fn generate_accelerations(
@@ -80,23 +458,25 @@ pub fn generate_accelerations(
     steps: usize,
     acceleration: f32,
     vary_speed: bool,
+    dt: f32,
 ) -> DVector<f32> {
     if vary_speed {
         let mut accelerations = DVector::zeros(steps);
 
-        // Choose a new acceleration every INTERVAL steps and lerp
-        const INTERVAL: usize = 50;
+        // Choose a new acceleration every INTERVAL time units and lerp
+        const INTERVAL: f32 = 50.0;
+        let interval = ((INTERVAL / dt).round() as usize).max(1);
         let mut prev_key = 0.0;
         let mut next_key = rng.gen::<f32>() * acceleration;
 
         for i in 0..steps {
-            let offset = i.rem_euclid(INTERVAL);
+            let offset = i.rem_euclid(interval);
             if offset == 0 {
                 prev_key = next_key;
                 next_key = rng.gen::<f32>() * acceleration;
             }
 
-            let factor = (offset as f32) / (INTERVAL as f32);
+            let factor = (offset as f32) / (interval as f32);
             accelerations[i] = prev_key + (next_key - prev_key) * factor;
         }
 
@@ -106,15 +486,141 @@ pub fn generate_accelerations(
     }
 }
 
+/// Positions of the agent, starting at the nest before the first state.
 pub fn reconstruct_path(states: &[PhysicalState]) -> Vec<Vector2<f32>> {
-    let mut position = Vector2::<f32>::zeros();
-    let mut path = Vec::with_capacity(states.len());
+    let mut path = Vec::with_capacity(states.len() + 1);
+    path.push(Vector2::zeros());
+    path.extend(states.iter().map(|state| state.position));
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    path.push(position);
-    for state in states {
-        position += state.velocity;
-        path.push(position);
+    /// Where the agent gets to after `duration` time units of turning and accelerating.
+    fn end_point(time_step: TimeStep, duration: f32) -> Vector2<f32> {
+        let steps = (duration / time_step.dt()).round() as usize;
+        let mut state = PhysicalState::default();
+        for _ in 0..steps {
+            state = state.advance(
+                0.05,
+                0.15,
+                DEFAULT_DRAG,
+                Vector2::zeros(),
+                &time_step,
+                &World::default(),
+            );
+        }
+        state.position
     }
 
-    path
+    #[test]
+    fn finer_time_steps_converge() {
+        let duration = 100.0;
+        let reference = end_point(TimeStep::new(1.0 / 64.0, 16).unwrap(), duration);
+        let error = |dt: f32, substeps: usize| {
+            (end_point(TimeStep::new(dt, substeps).unwrap(), duration) - reference).magnitude()
+        };
+
+        let by_dt = [
+            error(1.0, 1),
+            error(0.5, 1),
+            error(0.25, 1),
+            error(0.125, 1),
+        ];
+        let by_substeps = [error(1.0, 1), error(1.0, 2), error(1.0, 4), error(1.0, 8)];
+        for errors in [by_dt, by_substeps] {
+            assert!(errors.windows(2).all(|pair| pair[1] < pair[0]));
+            assert!(errors[3] < errors[0] / 4.0);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_time_steps() {
+        let time_step = |spec: &str| serde_json::from_str::<TimeStep>(spec);
+        assert_eq!(time_step(r#"{ "dt": 0.5 }"#).unwrap().dt(), 0.5);
+        assert!(time_step(r#"{ "dt": 0.0 }"#).is_err());
+        assert!(time_step(r#"{ "dt": -1.0 }"#).is_err());
+        assert!(time_step(r#"{ "substeps": 0 }"#).is_err());
+        assert!(TimeStep::new(f32::INFINITY, 1).is_err());
+        assert!(TimeStep::new(f32::NAN, 1).is_err());
+    }
+
+    /// Wind the agent drifted in at each step of an outbound route with gusts.
+    fn gusts(seed: u64, wind_seed: &str) -> Vec<Vector2<f32>> {
+        let spec = format!(
+            r#"
+            seed = {seed}
+
+            [setup]
+            outbound_steps = 100
+            inbound_steps = 0
+            acceleration_out = 0.15
+            acceleration_in = 0.1
+            vary_speed = true
+            record_memory = false
+            wind = {{ type = "gusting", mean = [0.0, 0.0], strength = 0.1, period = 5.0 {wind_seed} }}
+
+            [model]
+            variant = "reference"
+            "#
+        );
+        let flight = crate::experiment::Experiment::from_toml(&spec)
+            .unwrap()
+            .run();
+        flight
+            .physical_states
+            .iter()
+            .map(|state| state.wind)
+            .collect()
+    }
+
+    #[test]
+    fn trials_have_their_own_gusts() {
+        assert_ne!(gusts(1, ""), gusts(2, ""));
+        assert_eq!(gusts(1, ""), gusts(1, ""));
+        assert_eq!(gusts(1, ", seed = 7"), gusts(2, ", seed = 7"));
+    }
+
+    #[test]
+    fn wind_carries_the_agent_off_its_air_path() {
+        let route = |wind: &str| {
+            let spec = format!(
+                r#"
+                seed = 5
+
+                [setup]
+                outbound_steps = 200
+                inbound_steps = 0
+                acceleration_out = 0.15
+                acceleration_in = 0.1
+                vary_speed = true
+                record_memory = false
+                time_step = {{ dt = 0.5 }}
+                {wind}
+
+                [model]
+                variant = "reference"
+                "#
+            );
+            crate::experiment::Experiment::from_toml(&spec)
+                .unwrap()
+                .run()
+                .physical_states
+        };
+        let calm = route("");
+        let windy = route(r#"wind = { type = "constant", velocity = [0.2, -0.1] }"#);
+
+        // The same flight through the air, drifting with the wind over the ground
+        let wind = Vector2::new(0.2, -0.1);
+        for (i, (calm, windy)) in calm.iter().zip(&windy).enumerate() {
+            assert_eq!(windy.velocity, calm.velocity);
+            assert_eq!(windy.heading, calm.heading);
+            assert_eq!(windy.wind, wind);
+            assert_eq!(windy.ground_velocity(), windy.velocity + wind);
+            let drift = wind * 0.5 * (i + 1) as f32;
+            assert!((windy.position - calm.position - drift).magnitude() < 1e-3);
+        }
+    }
 }
@@ -0,0 +1,389 @@
+//! Outbound route generators, deciding how the agent turns on its way out.
+//!
+//! The speed profile is shared by all generators and drawn after the turns,
+//! so a random walk reproduces the routes of `movement::generate_outbound` exactly.
+//!
+//! In a spec, the generator is part of the setup:
+//!
+//! ```toml
+//! [setup.outbound]
+//! type = "waypoints"
+//! points = [[0.0, 200.0], [150.0, 200.0]]
+//! ```
+
+use std::fmt;
+
+use nalgebra::{DVector, Vector2};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{decode::wrap_angle, movement::PhysicalState};
+
+/// Decides how the agent turns on an outbound route.
+pub trait OutboundGenerator {
+    /// Turning rate at `step` of the route, given the agent's current state.
+    fn turn(&mut self, step: usize, state: &PhysicalState) -> f32;
+}
+
+/// Which generator to use for a route.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Outbound {
+    /// Normal(0, 0.1) turns per unit time smoothed over two steps, as in Stone et al. (2017).
+    #[default]
+    RandomWalk,
+    /// Independent von Mises(0, `kappa`) turns per unit time.
+    VonMises {
+        #[serde(default = "default_kappa")]
+        kappa: f32,
+    },
+    Straight,
+    /// Straight, with a single turn by `angle` after `turn_at` steps.
+    LShaped {
+        turn_at: usize,
+        #[serde(default = "default_l_angle")]
+        angle: f32,
+    },
+    /// Steers to each point in turn, turning at a rate of at most `max_turn` per unit time,
+    /// so by at most `max_turn * dt` in a step.
+    Waypoints {
+        points: Vec<Vector2<f32>>,
+        #[serde(default = "default_waypoint_radius")]
+        radius: f32,
+        #[serde(default = "default_max_turn")]
+        max_turn: f32,
+    },
+    /// Straight segments in uniformly random directions.
+    Levy(LevyFlight),
+}
+
+fn default_kappa() -> f32 {
+    100.0
+}
+
+fn default_l_angle() -> f32 {
+    std::f32::consts::FRAC_PI_2
+}
+
+fn default_waypoint_radius() -> f32 {
+    5.0
+}
+
+fn default_max_turn() -> f32 {
+    0.2
+}
+
+/// Segment durations of a Lévy flight, drawn from a power law with exponent `mu`
+/// and starting at `min_length` time units.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "LevyFlightSpec")]
+pub struct LevyFlight {
+    mu: f32,
+    min_length: f32,
+}
+
+/// Lévy flight parameters as given in a spec, before they are checked.
+#[derive(Deserialize)]
+struct LevyFlightSpec {
+    #[serde(default = "default_levy_mu")]
+    mu: f32,
+    #[serde(default = "default_levy_min_length")]
+    min_length: f32,
+}
+
+fn default_levy_mu() -> f32 {
+    2.0
+}
+
+fn default_levy_min_length() -> f32 {
+    10.0
+}
+
+#[derive(Debug)]
+pub struct InvalidLevyFlight {
+    pub mu: f32,
+    pub min_length: f32,
+}
+
+impl fmt::Display for InvalidLevyFlight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Lévy flight must have a finite exponent above 1 and a finite, positive shortest segment, got exponent {} with shortest segment {}",
+            self.mu, self.min_length
+        )
+    }
+}
+
+impl std::error::Error for InvalidLevyFlight {}
+
+impl Default for LevyFlight {
+    fn default() -> Self {
+        LevyFlight {
+            mu: default_levy_mu(),
+            min_length: default_levy_min_length(),
+        }
+    }
+}
+
+impl TryFrom<LevyFlightSpec> for LevyFlight {
+    type Error = InvalidLevyFlight;
+
+    fn try_from(spec: LevyFlightSpec) -> Result<Self, Self::Error> {
+        LevyFlight::new(spec.mu, spec.min_length)
+    }
+}
+
+impl LevyFlight {
+    pub fn new(mu: f32, min_length: f32) -> Result<LevyFlight, InvalidLevyFlight> {
+        if mu.is_finite() && mu > 1.0 && min_length.is_finite() && min_length > 0.0 {
+            Ok(LevyFlight { mu, min_length })
+        } else {
+            Err(InvalidLevyFlight { mu, min_length })
+        }
+    }
+
+    pub fn mu(&self) -> f32 {
+        self.mu
+    }
+
+    pub fn min_length(&self) -> f32 {
+        self.min_length
+    }
+
+    /// A segment duration, from a uniform sample `u` in (0, 1].
+    fn duration(&self, u: f32) -> f32 {
+        self.min_length * u.powf(-1.0 / (self.mu - 1.0))
+    }
+}
+
+impl Outbound {
+    /// Draws what the route needs up front, so the generator itself holds no random state.
+    ///
+    /// Random turns diffuse the heading at the same rate per unit time whatever the step `dt`,
+    /// and single turns happen within one step.
+    pub fn generator(&self, rng: &mut impl Rng, steps: usize, dt: f32) -> Generator {
+        match *self {
+            Outbound::RandomWalk => {
+                Generator::Turns(crate::movement::generate_rotations(rng, steps) / dt.sqrt())
+            }
+            Outbound::VonMises { kappa } => Generator::Turns(DVector::from_iterator(
+                steps,
+                (0..steps).map(|_| sample_von_mises(rng, kappa) / dt.sqrt()),
+            )),
+            Outbound::Straight => Generator::Turns(DVector::zeros(steps)),
+            Outbound::LShaped { turn_at, angle } => {
+                let mut turns = DVector::zeros(steps);
+                if turn_at < steps {
+                    turns[turn_at] = angle / dt;
+                }
+                Generator::Turns(turns)
+            }
+            Outbound::Waypoints {
+                ref points,
+                radius,
+                max_turn,
+            } => Generator::Waypoints(WaypointSteering {
+                points: points.clone(),
+                radius,
+                max_turn,
+                next: 0,
+                dt,
+            }),
+            Outbound::Levy(levy) => {
+                let mut turns = DVector::zeros(steps);
+                let mut step = 0;
+                while step < steps {
+                    turns[step] = rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI) / dt;
+                    let u: f32 = 1.0 - rng.gen::<f32>();
+                    // Segments shorter than a step still take one
+                    let length = levy.duration(u) / dt;
+                    step += length.clamp(1.0, steps as f32) as usize;
+                }
+                Generator::Turns(turns)
+            }
+        }
+    }
+}
+
+/// A built-in generator in progress.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Generator {
+    /// Open-loop turns, one per step.
+    Turns(DVector<f32>),
+    Waypoints(WaypointSteering),
+}
+
+impl OutboundGenerator for Generator {
+    fn turn(&mut self, step: usize, state: &PhysicalState) -> f32 {
+        match self {
+            Generator::Turns(turns) => turns.get(step).copied().unwrap_or(0.0),
+            Generator::Waypoints(steering) => steering.turn(step, state),
+        }
+    }
+}
+
+/// Closed-loop steering towards a sequence of points; goes straight on after the last.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WaypointSteering {
+    pub points: Vec<Vector2<f32>>,
+    pub radius: f32,
+    /// Largest turning rate, per unit time like every turn, so the largest turn
+    /// in a step is `max_turn * dt`.
+    pub max_turn: f32,
+    /// Index of the point currently steered to.
+    pub next: usize,
+    /// Time per step, over which the steering tries to face the point.
+    #[serde(default = "default_dt")]
+    pub dt: f32,
+}
+
+fn default_dt() -> f32 {
+    1.0
+}
+
+impl OutboundGenerator for WaypointSteering {
+    fn turn(&mut self, _step: usize, state: &PhysicalState) -> f32 {
+        while self
+            .points
+            .get(self.next)
+            .is_some_and(|point| (point - state.position).magnitude() <= self.radius)
+        {
+            self.next += 1;
+        }
+        let Some(point) = self.points.get(self.next) else {
+            return 0.0;
+        };
+
+        let offset = point - state.position;
+        let bearing = offset.x.atan2(offset.y);
+        (wrap_angle(bearing - state.heading) / self.dt).clamp(-self.max_turn, self.max_turn)
+    }
+}
+
+/// Von Mises(0, `kappa`) sample by Best and Fisher's (1979) rejection method.
+fn sample_von_mises(rng: &mut impl Rng, kappa: f32) -> f32 {
+    if kappa < 1e-6 {
+        return rng.gen_range(-std::f32::consts::PI..std::f32::consts::PI);
+    }
+
+    let tau = 1.0 + (1.0 + 4.0 * kappa * kappa).sqrt();
+    let rho = (tau - (2.0 * tau).sqrt()) / (2.0 * kappa);
+    let r = (1.0 + rho * rho) / (2.0 * rho);
+
+    loop {
+        let (u1, u2, u3): (f32, f32, f32) = (rng.gen(), rng.gen(), rng.gen());
+        let z = (std::f32::consts::PI * u1).cos();
+        let f = (1.0 + r * z) / (r + z);
+        let c = kappa * (r - f);
+        if c * (2.0 - c) > u2 || (c / u2).ln() + 1.0 >= c {
+            let angle = f.clamp(-1.0, 1.0).acos();
+            return if u3 > 0.5 { angle } else { -angle };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::experiment::Experiment;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    fn turns(outbound: &Outbound, steps: usize, dt: f32) -> Vec<f32> {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        let mut generator = outbound.generator(&mut rng, steps, dt);
+        (0..steps)
+            .map(|step| generator.turn(step, &PhysicalState::default()))
+            .collect()
+    }
+
+    #[test]
+    fn von_mises_turns_have_the_given_concentration() {
+        let (kappa, dt) = (100.0, 0.25);
+        let turns = turns(&Outbound::VonMises { kappa }, 20000, dt);
+        // Mean resultant length of von Mises(0, 100), I1(100) / I0(100)
+        let mean_cos = turns
+            .iter()
+            .map(|turn| (turn * dt.sqrt()).cos())
+            .sum::<f32>()
+            / turns.len() as f32;
+        assert!((mean_cos - 0.99499).abs() < 5e-4);
+    }
+
+    #[test]
+    fn levy_segments_follow_the_power_law() {
+        let (levy, dt) = (LevyFlight::new(3.0, 20.0).unwrap(), 0.5);
+        let turns = turns(&Outbound::Levy(levy), 200_000, dt);
+        let starts: Vec<usize> = (0..turns.len())
+            .filter(|&step| turns[step] != 0.0)
+            .collect();
+        let durations: Vec<f32> = starts
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]) as f32 * dt)
+            .collect();
+
+        // Durations are floored to whole steps
+        assert!(durations.iter().all(|&duration| duration >= 20.0 - dt));
+        // The mean of a Pareto distribution, min_length * (mu - 1) / (mu - 2)
+        let mean = durations.iter().sum::<f32>() / durations.len() as f32;
+        assert!((mean - 40.0).abs() < 4.0);
+    }
+
+    #[test]
+    fn rejects_invalid_levy_flights() {
+        assert!(LevyFlight::new(1.0, 10.0).is_err());
+        assert!(LevyFlight::new(f32::NAN, 10.0).is_err());
+        assert!(LevyFlight::new(2.0, 0.0).is_err());
+        assert!(LevyFlight::new(2.0, f32::INFINITY).is_err());
+
+        let outbound = |spec: &str| toml::from_str::<Outbound>(spec);
+        let levy = outbound(r#"type = "levy""#).unwrap();
+        assert!(matches!(levy, Outbound::Levy(levy) if levy.mu() == 2.0));
+        assert!(outbound("type = \"levy\"\nmu = 0.5").is_err());
+        assert!(outbound("type = \"levy\"\nmin_length = -1.0").is_err());
+    }
+
+    #[test]
+    fn waypoint_routes_pass_each_point_turning_no_faster_than_allowed() {
+        let spec = r#"
+            seed = 1
+
+            [setup]
+            outbound_steps = 1200
+            inbound_steps = 0
+            acceleration_out = 0.15
+            acceleration_in = 0.1
+            vary_speed = false
+            record_memory = false
+            time_step = { dt = 0.5 }
+
+            [setup.outbound]
+            type = "waypoints"
+            points = [[0.0, 100.0], [100.0, 100.0], [100.0, 0.0]]
+            radius = 5.0
+            max_turn = 0.3
+
+            [model]
+            variant = "reference"
+        "#;
+        let states = Experiment::from_toml(spec).unwrap().run().physical_states;
+
+        let mut reached = 0;
+        for point in [
+            Vector2::new(0.0, 100.0),
+            Vector2::new(100.0, 100.0),
+            Vector2::new(100.0, 0.0),
+        ] {
+            reached += states[reached..]
+                .iter()
+                .position(|state| (state.position - point).magnitude() <= 5.0)
+                .unwrap();
+        }
+        for pair in states.windows(2) {
+            let turn = wrap_angle(pair[1].heading - pair[0].heading);
+            assert!(turn.abs() <= 0.3 * 0.5 + 1e-5);
+        }
+    }
+}
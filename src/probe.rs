@@ -0,0 +1,201 @@
+//! Probes that record the activity of selected neuron populations during a flight.
+//!
+//! In a spec, probes are part of the setup:
+//!
+//! ```toml
+//! [setup.probes]
+//! populations = ["tb1", "cpu1a", "motor"]
+//! every = 10
+//! ```
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::Activity;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Population {
+    Tl2,
+    Cl1,
+    Tb1,
+    Tn1,
+    Tn2,
+    Cpu4,
+    Pontine,
+    Amp,
+    Cpu1a,
+    Cpu1b,
+    /// The turning signal, as a single value.
+    Motor,
+}
+
+impl Population {
+    pub const ALL: [Population; 11] = [
+        Population::Tl2,
+        Population::Cl1,
+        Population::Tb1,
+        Population::Tn1,
+        Population::Tn2,
+        Population::Cpu4,
+        Population::Pontine,
+        Population::Amp,
+        Population::Cpu1a,
+        Population::Cpu1b,
+        Population::Motor,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Population::Tl2 => "tl2",
+            Population::Cl1 => "cl1",
+            Population::Tb1 => "tb1",
+            Population::Tn1 => "tn1",
+            Population::Tn2 => "tn2",
+            Population::Cpu4 => "cpu4",
+            Population::Pontine => "pontine",
+            Population::Amp => "amp",
+            Population::Cpu1a => "cpu1a",
+            Population::Cpu1b => "cpu1b",
+            Population::Motor => "motor",
+        }
+    }
+
+    pub fn read(self, activity: &Activity) -> Vec<f32> {
+        match self {
+            Population::Tl2 => activity.tl2.as_slice().to_vec(),
+            Population::Cl1 => activity.cl1.as_slice().to_vec(),
+            Population::Tb1 => activity.tb1.as_slice().to_vec(),
+            Population::Tn1 => activity.tn1.as_slice().to_vec(),
+            Population::Tn2 => activity.tn2.as_slice().to_vec(),
+            Population::Cpu4 => activity.cpu4.as_slice().to_vec(),
+            Population::Pontine => activity.pontine.as_slice().to_vec(),
+            Population::Amp => activity.amp.as_slice().to_vec(),
+            Population::Cpu1a => activity.cpu1a.as_slice().to_vec(),
+            Population::Cpu1b => activity.cpu1b.as_slice().to_vec(),
+            Population::Motor => vec![activity.motor],
+        }
+    }
+}
+
+impl fmt::Display for Population {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Population {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Population::ALL
+            .into_iter()
+            .find(|population| population.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown population: {}", s))
+    }
+}
+
+/// Which populations to record, and how often.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Probes {
+    pub populations: Vec<Population>,
+    /// Records every `every`th physical state.
+    pub every: usize,
+}
+
+impl Default for Probes {
+    fn default() -> Self {
+        Probes {
+            populations: Vec::new(),
+            every: 1,
+        }
+    }
+}
+
+impl Probes {
+    pub fn is_empty(&self) -> bool {
+        self.populations.is_empty()
+    }
+}
+
+/// Recorded activities, aligned like the memory record: sample `i` is the activity
+/// after the network has seen physical state `steps[i]`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProbeRecord {
+    pub steps: Vec<usize>,
+    pub activities: BTreeMap<Population, Vec<Vec<f32>>>,
+}
+
+impl ProbeRecord {
+    pub fn new(probes: &Probes) -> Self {
+        ProbeRecord {
+            steps: Vec::new(),
+            activities: probes
+                .populations
+                .iter()
+                .map(|&population| (population, Vec::new()))
+                .collect(),
+        }
+    }
+
+    /// Records the activity after the network has seen the given state, if it is due.
+    pub fn sample(&mut self, every: usize, step: usize, activity: &Activity) {
+        if !step.is_multiple_of(every.max(1)) {
+            return;
+        }
+        self.steps.push(step);
+        for (population, samples) in self.activities.iter_mut() {
+            samples.push(population.read(activity));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        experiment::Experiment,
+        model::constants::{N_CPU1A, N_TB1},
+    };
+
+    const SPEC: &str = r#"
+        seed = 2
+
+        [setup]
+        outbound_steps = 100
+        inbound_steps = 100
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = true
+        probes = { populations = ["tb1", "cpu1a", "motor"], every = 10 }
+
+        [model]
+        variant = "reference"
+    "#;
+
+    #[test]
+    fn probes_record_the_requested_populations() {
+        let flight = Experiment::from_toml(SPEC).unwrap().run();
+        let record = flight.probe_record.unwrap();
+
+        let populations: Vec<_> = record.activities.keys().copied().collect();
+        assert_eq!(
+            populations,
+            [Population::Tb1, Population::Cpu1a, Population::Motor]
+        );
+        let steps: Vec<_> = (0..200).step_by(10).collect();
+        assert_eq!(record.steps, steps);
+        for (population, size) in [
+            (Population::Tb1, N_TB1),
+            (Population::Cpu1a, N_CPU1A),
+            (Population::Motor, 1),
+        ] {
+            let samples = &record.activities[&population];
+            assert_eq!(samples.len(), steps.len());
+            assert!(samples.iter().all(|sample| sample.len() == size));
+        }
+    }
+}
@@ -0,0 +1,880 @@
+//! Trial protocols as sequences of phases, each with its own control source.
+//!
+//! Every step appends one physical state to the flight. Except for displacements, the network is
+//! first updated with the current state, and the phase's control source then decides how the agent
+//! moves on; during a route, the network's motor output is ignored. Unless the agent is replayed or
+//! displaced, the setup's wind carries it along.
+//!
+//! A protocol can be given in an experiment spec, e.g. in TOML:
+//!
+//! ```toml
+//! [[protocol]]
+//! name = "outbound"
+//! control = { type = "route", steps = 1500, acceleration = 0.15, vary_speed = true }
+//!
+//! [[protocol]]
+//! name = "detour"
+//! control = { type = "route", steps = 300, acceleration = 0.15, vary_speed = false, generator = { type = "straight" } }
+//!
+//! [[protocol]]
+//! name = "pause"
+//! control = { type = "pause", steps = 100 }
+//!
+//! [[protocol]]
+//! name = "homing"
+//! control = { type = "homing", steps = 1500, acceleration = 0.1, stop = { radius = 20.0 } }
+//! record_memory = true
+//! ```
+//!
+//! A `feed` phase stores the agent's vector memory of the feeder it is at, and a later
+//! `to_feeder` phase steers back to it, e.g. after homing.
+
+use nalgebra::{DVector, SVector, Vector2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    decode::decode_home_vector,
+    model::{constants::N_CPU4, memory::MemoryRecorder, Config, Goal, CX},
+    movement::{self, Altitude, Capture, Displacement, PhysicalState, DEFAULT_DRAG},
+    outbound::{Generator, Outbound, OutboundGenerator},
+    probe::ProbeRecord,
+    sink::{Sink, StepRecord},
+    FlightData, Setup,
+};
+
+pub const DEFAULT_SEARCH_TURN: f32 = 0.3;
+pub const DEFAULT_SEARCH_GROWTH: f32 = 0.01;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Control {
+    /// Follows a random route, drawn from the network's route stream when the phase starts.
+    Route {
+        steps: usize,
+        acceleration: f32,
+        vary_speed: bool,
+        #[serde(default)]
+        generator: Outbound,
+        #[serde(default)]
+        altitude: Altitude,
+    },
+    /// Follows the given physical states, e.g. a pre-generated or recorded route.
+    Replay { states: Vec<PhysicalState> },
+    /// Stands still; the agent comes to rest under drag while the network keeps running.
+    Pause { steps: usize },
+    /// Picks the agent up and puts it down elsewhere in a single step, without updating the network.
+    Displace(Displacement),
+    /// Steers with the network's motor output, for at most `steps` steps.
+    Homing {
+        steps: usize,
+        acceleration: f32,
+        #[serde(default)]
+        stop: StopConditions,
+        #[serde(default)]
+        altitude: Altitude,
+    },
+    /// Systematic search in an outward spiral, turning at `turn / (1 + growth * t)` at time `t` into the phase.
+    Search {
+        steps: usize,
+        acceleration: f32,
+        #[serde(default = "default_search_turn")]
+        turn: f32,
+        #[serde(default = "default_search_growth")]
+        growth: f32,
+    },
+    /// Rests at a feeder; on arrival, the path integration memory is stored as the feeder's vector.
+    Feed { steps: usize },
+    /// Steers with the network towards the last stored feeder vector, for at most `steps` steps.
+    ToFeeder {
+        steps: usize,
+        acceleration: f32,
+        #[serde(default)]
+        stop: StopConditions,
+        #[serde(default)]
+        altitude: Altitude,
+        /// Where the feeder is, for the stop radius; by default, where the agent last fed in this flight.
+        #[serde(default)]
+        feeder: Option<Vector2<f32>>,
+    },
+}
+
+fn default_search_turn() -> f32 {
+    DEFAULT_SEARCH_TURN
+}
+
+fn default_search_growth() -> f32 {
+    DEFAULT_SEARCH_GROWTH
+}
+
+/// Conditions that end a homing or feeder phase before its step limit.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StopConditions {
+    /// Stops once the agent is within this distance of its goal, the nest or the feeder.
+    pub radius: Option<f32>,
+    /// Stops once the home vector decoded from the memory has shrunk to this fraction
+    /// of its length at the start of the phase. Only applies to homing, since the home vector
+    /// grows on the way to a feeder.
+    pub memory: Option<f32>,
+    /// Stops once the agent starts to search.
+    pub search: Option<SearchOnset>,
+}
+
+/// Search is taken to start when, over the last `window` steps of the phase,
+/// the net displacement is at most `straightness` times the distance travelled.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchOnset {
+    pub window: usize,
+    pub straightness: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    StepLimit,
+    ReachedHome,
+    ReachedFeeder,
+    MemoryExhausted,
+    SearchOnset,
+}
+
+/// Why a phase ended, and after how many steps.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Termination {
+    pub reason: StopReason,
+    pub steps: usize,
+}
+
+impl StopConditions {
+    /// Checks the conditions; the radius is around the goal, and reaching it the given reason.
+    /// `home_vector` is the decoded home vector's length as a fraction of that at the start of the phase.
+    fn check(
+        &self,
+        states: &[PhysicalState],
+        phase_steps: usize,
+        home_vector: Option<f32>,
+        goal: Option<(Vector2<f32>, StopReason)>,
+    ) -> Option<StopReason> {
+        let position = states.last()?.position;
+        if let (Some(radius), Some((goal, reached))) = (self.radius, goal) {
+            if (position - goal).magnitude() <= radius {
+                return Some(reached);
+            }
+        }
+
+        if let (Some(threshold), Some(home_vector)) = (self.memory, home_vector) {
+            if home_vector <= threshold {
+                return Some(StopReason::MemoryExhausted);
+            }
+        }
+
+        if let Some(ref search) = self.search {
+            if search.window > 0 && phase_steps >= search.window {
+                let window = states.get(states.len().checked_sub(search.window + 1)?..)?;
+                let travelled: f32 = window
+                    .windows(2)
+                    .map(|pair| (pair[1].position - pair[0].position).magnitude())
+                    .sum();
+                let net = (position - window[0].position).magnitude();
+                if travelled > 0.0 && net <= search.straightness * travelled {
+                    return Some(StopReason::SearchOnset);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlKind {
+    Route,
+    Replay,
+    Pause,
+    Displace,
+    Homing,
+    Search,
+    Feed,
+    ToFeeder,
+}
+
+impl Control {
+    pub fn kind(&self) -> ControlKind {
+        match self {
+            Control::Route { .. } => ControlKind::Route,
+            Control::Replay { .. } => ControlKind::Replay,
+            Control::Pause { .. } => ControlKind::Pause,
+            Control::Displace { .. } => ControlKind::Displace,
+            Control::Homing { .. } => ControlKind::Homing,
+            Control::Search { .. } => ControlKind::Search,
+            Control::Feed { .. } => ControlKind::Feed,
+            Control::ToFeeder { .. } => ControlKind::ToFeeder,
+        }
+    }
+
+    pub fn steps(&self) -> usize {
+        match *self {
+            Control::Replay { ref states } => states.len(),
+            Control::Displace { .. } => 1,
+            Control::Route { steps, .. }
+            | Control::Pause { steps }
+            | Control::Homing { steps, .. }
+            | Control::Search { steps, .. }
+            | Control::Feed { steps }
+            | Control::ToFeeder { steps, .. } => steps,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Phase {
+    pub name: String,
+    pub control: Control,
+    #[serde(default)]
+    pub record_memory: bool,
+    /// Whether the agent learns views in this phase, if it has vision.
+    #[serde(default)]
+    pub learn_views: bool,
+}
+
+/// What a flight records about each phase of its protocol.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PhaseLabel {
+    pub name: String,
+    pub control: ControlKind,
+    /// Set once the phase has ended.
+    #[serde(default)]
+    pub termination: Option<Termination>,
+}
+
+impl From<&Phase> for PhaseLabel {
+    fn from(phase: &Phase) -> Self {
+        PhaseLabel {
+            name: phase.name.clone(),
+            control: phase.control.kind(),
+            termination: None,
+        }
+    }
+}
+
+/// Motor program of a route phase.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MotorProgram {
+    generator: Generator,
+    accelerations: DVector<f32>,
+}
+
+/// Position within a protocol.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Progress {
+    pub phase: usize,
+    pub step: usize,
+    program: Option<MotorProgram>,
+    /// Length of the decoded home vector at the start of the phase.
+    #[serde(default)]
+    home_vector: Option<f32>,
+}
+
+impl Progress {
+    /// Records why the current phase ended and moves on to the next one.
+    fn end_phase(&mut self, flight: &mut FlightData, reason: StopReason) {
+        flight.phases[self.phase].termination = Some(Termination {
+            reason,
+            steps: self.step,
+        });
+        self.phase += 1;
+        self.step = 0;
+        self.program = None;
+        self.home_vector = None;
+    }
+
+    /// Ends phases until one still has steps left.
+    fn skip_finished(&mut self, protocol: &[Phase], flight: &mut FlightData) {
+        while self.phase < protocol.len() && self.step >= protocol[self.phase].control.steps() {
+            self.end_phase(flight, StopReason::StepLimit);
+        }
+    }
+}
+
+impl Setup {
+    /// The classic protocol: a random outbound route followed by homing,
+    /// with the displacement inserted if there is one.
+    pub fn protocol(&self) -> Vec<Phase> {
+        self.protocol_with_outbound(self.outbound_route())
+    }
+
+    /// A random outbound route as the setup describes it.
+    pub fn outbound_route(&self) -> Control {
+        Control::Route {
+            steps: self.outbound_steps,
+            acceleration: self.acceleration_out,
+            vary_speed: self.vary_speed,
+            generator: self.outbound.clone(),
+            altitude: self.outbound_altitude.clone(),
+        }
+    }
+
+    pub fn protocol_with_outbound(&self, outbound: Control) -> Vec<Phase> {
+        let TripPhases {
+            outbound, homing, ..
+        } = outbound_phases(self, outbound, None);
+
+        match self.displacement {
+            None => vec![outbound, homing],
+            Some(ref displacement) => {
+                let displace = self.phase("displacement", Control::Displace(displacement.clone()));
+                match displacement.capture {
+                    Capture::Feeder => vec![outbound, displace, homing],
+                    Capture::Nest => vec![
+                        outbound,
+                        homing.clone(),
+                        displace,
+                        Phase {
+                            name: "release".into(),
+                            ..homing
+                        },
+                    ],
+                }
+            }
+        }
+    }
+
+    /// Adds a feed at the end of the outbound phase and, after the rest of the protocol,
+    /// travel back to the feeder with the same limits as homing.
+    pub fn with_revisit(&self, mut protocol: Vec<Phase>) -> Vec<Phase> {
+        let TripPhases { feed, revisit, .. } = outbound_phases(self, self.outbound_route(), None);
+        protocol.insert(1.min(protocol.len()), feed);
+        protocol.push(revisit);
+        protocol
+    }
+
+    /// A phase that records memory if the setup does.
+    pub fn phase(&self, name: &str, control: Control) -> Phase {
+        Phase {
+            name: name.into(),
+            control,
+            record_memory: self.record_memory,
+            learn_views: false,
+        }
+    }
+}
+
+/// The phases of a trip out to a feeder and back that protocols are put together from.
+pub struct TripPhases {
+    /// Learns views if the agent has vision.
+    pub outbound: Phase,
+    /// Stores the feeder vector where the outbound phase ends.
+    pub feed: Phase,
+    pub homing: Phase,
+    /// Travel back to the feeder.
+    pub revisit: Phase,
+}
+
+/// The phases of a trip with the given outbound control, all with the setup's recording,
+/// step limits, stop conditions and altitudes. The revisit heads for `feeder`, or by default
+/// for where the agent last fed.
+pub fn outbound_phases(
+    setup: &Setup,
+    outbound: Control,
+    feeder: Option<Vector2<f32>>,
+) -> TripPhases {
+    TripPhases {
+        outbound: Phase {
+            learn_views: setup.vision.is_some(),
+            ..setup.phase("outbound", outbound)
+        },
+        feed: setup.phase("feed", Control::Feed { steps: 1 }),
+        homing: setup.phase(
+            "homing",
+            Control::Homing {
+                steps: setup.inbound_steps,
+                acceleration: setup.acceleration_in,
+                stop: setup.stop.clone(),
+                altitude: setup.inbound_altitude.clone(),
+            },
+        ),
+        revisit: setup.phase(
+            "revisit",
+            Control::ToFeeder {
+                steps: setup.inbound_steps,
+                acceleration: setup.acceleration_in,
+                stop: setup.stop.clone(),
+                altitude: setup.inbound_altitude.clone(),
+                feeder,
+            },
+        ),
+    }
+}
+
+impl FlightData {
+    pub fn new(setup: &Setup, protocol: &[Phase]) -> FlightData {
+        let steps = protocol.iter().map(|phase| phase.control.steps()).sum();
+        let record_memory = protocol.iter().any(|phase| phase.record_memory);
+        FlightData {
+            setup: setup.clone(),
+            phases: protocol.iter().map(PhaseLabel::from).collect(),
+            step_phases: Vec::with_capacity(steps),
+            physical_states: Vec::with_capacity(steps),
+            memory_record: record_memory.then(|| Vec::with_capacity(steps)),
+            probe_record: (!setup.probes.is_empty()).then(|| ProbeRecord::new(&setup.probes)),
+            feeders: Vec::new(),
+            retain: None,
+            dropped: 0,
+            initial: PhysicalState::default(),
+        }
+    }
+
+    /// A flight that picks up where `previous` left off, with the agent where it was
+    /// and the clock running on.
+    pub fn continuing(setup: &Setup, protocol: &[Phase], previous: &FlightData) -> FlightData {
+        FlightData {
+            initial: previous.current().clone(),
+            dropped: previous.step_count(),
+            ..FlightData::new(setup, protocol)
+        }
+    }
+
+    /// A flight that keeps only the phases and as many of the latest steps as the stop
+    /// conditions look back over, for runs whose steps all go to a sink.
+    /// Memory and probes are not recorded.
+    pub fn streaming(setup: &Setup, protocol: &[Phase]) -> FlightData {
+        let retain = protocol
+            .iter()
+            .filter_map(|phase| match phase.control {
+                Control::Homing { ref stop, .. } | Control::ToFeeder { ref stop, .. } => {
+                    stop.search.as_ref().map(|search| search.window + 1)
+                }
+                _ => None,
+            })
+            .fold(1, usize::max);
+        FlightData {
+            setup: setup.clone(),
+            phases: protocol.iter().map(PhaseLabel::from).collect(),
+            step_phases: Vec::with_capacity(2 * retain),
+            physical_states: Vec::with_capacity(2 * retain),
+            memory_record: None,
+            probe_record: None,
+            feeders: Vec::new(),
+            retain: Some(retain),
+            dropped: 0,
+            initial: PhysicalState::default(),
+        }
+    }
+
+    /// Number of steps so far, including those no longer kept.
+    pub fn step_count(&self) -> usize {
+        self.dropped + self.physical_states.len()
+    }
+
+    /// Where the agent is now.
+    pub fn current(&self) -> &PhysicalState {
+        self.physical_states.last().unwrap_or(&self.initial)
+    }
+
+    /// Where the agent was before the kept step with the given index.
+    pub fn before(&self, step: usize) -> &PhysicalState {
+        match step {
+            0 => &self.initial,
+            _ => &self.physical_states[step - 1],
+        }
+    }
+
+    /// Appends a step, letting go of old steps beyond those to retain.
+    fn push(&mut self, state: PhysicalState, phase: usize) {
+        self.physical_states.push(state);
+        self.step_phases.push(phase);
+        if let Some(retain) = self.retain {
+            if self.physical_states.len() >= 2 * retain {
+                let drop = self.physical_states.len() - retain;
+                self.physical_states.drain(..drop);
+                self.step_phases.drain(..drop);
+                self.dropped += drop;
+            }
+        }
+    }
+
+    /// Indices of the physical states produced by phases with the given control.
+    pub fn steps_with(&self, control: ControlKind) -> impl Iterator<Item = usize> + '_ {
+        self.step_phases
+            .iter()
+            .enumerate()
+            .filter(move |&(_, &phase)| self.phases[phase].control == control)
+            .map(|(step, _)| step)
+    }
+
+    /// Indices of the physical states produced by the phase with the given name.
+    pub fn steps_in(&self, name: &str) -> impl Iterator<Item = usize> + '_ {
+        let name = name.to_owned();
+        self.step_phases
+            .iter()
+            .enumerate()
+            .filter(move |&(_, &phase)| self.phases[phase].name == name)
+            .map(|(step, _)| step)
+    }
+}
+
+pub fn run_protocol<C: Config>(cx: &mut CX<C>, setup: &Setup, protocol: &[Phase]) -> FlightData {
+    run_protocol_with(cx, setup, protocol, &mut ())
+}
+
+/// Runs the protocol, passing every step to the sink as it happens.
+pub fn run_protocol_with<C: Config>(
+    cx: &mut CX<C>,
+    setup: &Setup,
+    protocol: &[Phase],
+    sink: &mut impl Sink,
+) -> FlightData {
+    run_flight(cx, protocol, FlightData::new(setup, protocol), sink)
+}
+
+/// Runs the protocol on from where the previous flight ended, e.g. on the next of many trips.
+pub fn continue_protocol<C: Config>(
+    cx: &mut CX<C>,
+    setup: &Setup,
+    protocol: &[Phase],
+    previous: &FlightData,
+) -> FlightData {
+    let flight = FlightData::continuing(setup, protocol, previous);
+    run_flight(cx, protocol, flight, &mut ())
+}
+
+/// Like [`run_protocol_with`], keeping only what the stop conditions need of the flight,
+/// so that memory use does not grow with its length. See [`FlightData::streaming`].
+pub fn run_protocol_streaming<C: Config>(
+    cx: &mut CX<C>,
+    setup: &Setup,
+    protocol: &[Phase],
+    sink: &mut impl Sink,
+) -> FlightData {
+    run_flight(cx, protocol, FlightData::streaming(setup, protocol), sink)
+}
+
+fn run_flight<C: Config>(
+    cx: &mut CX<C>,
+    protocol: &[Phase],
+    mut flight: FlightData,
+    sink: &mut impl Sink,
+) -> FlightData {
+    advance_with(
+        cx,
+        protocol,
+        &mut flight,
+        &mut Progress::default(),
+        usize::MAX,
+        sink,
+    );
+    flight
+}
+
+/// Runs at most `steps` steps of the protocol. Returns whether the protocol is complete.
+///
+/// A homing phase ends early once one of its stop conditions is met.
+pub fn advance<C: Config>(
+    cx: &mut CX<C>,
+    protocol: &[Phase],
+    flight: &mut FlightData,
+    progress: &mut Progress,
+    steps: usize,
+) -> bool {
+    advance_with(cx, protocol, flight, progress, steps, &mut ())
+}
+
+/// Like [`advance`], passing every step to the sink.
+pub fn advance_with<C: Config>(
+    cx: &mut CX<C>,
+    protocol: &[Phase],
+    flight: &mut FlightData,
+    progress: &mut Progress,
+    steps: usize,
+    sink: &mut impl Sink,
+) -> bool {
+    for _ in 0..steps {
+        progress.skip_finished(protocol, flight);
+        let Some(phase) = protocol.get(progress.phase) else {
+            break;
+        };
+
+        let stop = step(cx, phase, progress, flight, sink);
+        progress.step += 1;
+        if let Some(reason) = stop {
+            progress.end_phase(flight, reason);
+        }
+    }
+
+    progress.skip_finished(protocol, flight);
+    progress.phase >= protocol.len()
+}
+
+fn step<C: Config>(
+    cx: &mut CX<C>,
+    phase: &Phase,
+    progress: &mut Progress,
+    flight: &mut FlightData,
+    sink: &mut impl Sink,
+) -> Option<StopReason> {
+    let current = flight.physical_states.last().unwrap_or(&flight.initial);
+
+    // Feed the current state to the network, unless the agent is being carried
+    // or there is no current state yet
+    let stop = match phase.control {
+        Control::Homing { ref stop, .. } | Control::ToFeeder { ref stop, .. } => Some(stop),
+        _ => None,
+    };
+    let memory_stop = phase.control.kind() == ControlKind::Homing
+        && stop.is_some_and(|stop| stop.memory.is_some());
+    let goal = match phase.control.kind() {
+        ControlKind::ToFeeder => Goal::Vector,
+        _ => Goal::Home,
+    };
+    let (motor, memory) =
+        if flight.step_count() == 0 || phase.control.kind() == ControlKind::Displace {
+            (0.0, None)
+        } else {
+            let time_step = flight.setup.time_step;
+            let mut motor = cx.update(
+                current,
+                &flight.setup.senses,
+                goal,
+                time_step.time(flight.step_count() - 1),
+                time_step.dt(),
+            );
+            if phase.control.kind() == ControlKind::Feed && progress.step == 0 {
+                cx.store_vector();
+                flight.feeders.push(current.position);
+            }
+            if let Some(ref vision) = flight.setup.vision {
+                let world = &flight.setup.world;
+                let seed = cx.random_mut().vision_seed();
+                let visual_memory = cx.visual_memory.get_or_insert_with(|| vision.memory(seed));
+                if phase.learn_views {
+                    vision.learn(visual_memory, world, current, progress.step);
+                }
+                if phase.control.kind() == ControlKind::Homing {
+                    motor = vision.steer(visual_memory, world, current, motor);
+                }
+            }
+            // Samples are of kept states; a continuing flight starts from one it does not keep
+            let seen = flight.physical_states.len().checked_sub(1);
+            if let (Some(probe_record), Some(seen)) = (&mut flight.probe_record, seen) {
+                probe_record.sample(flight.setup.probes.every, seen, cx.activity());
+            }
+            let needs_memory = phase.record_memory || memory_stop;
+            let memory: Option<SVector<f32, N_CPU4>> =
+                needs_memory.then(|| C::MemoryRecorder::record(cx));
+            (motor, memory)
+        };
+
+    let time_step = flight.setup.time_step;
+    let world = &flight.setup.world;
+    let wind = flight.setup.wind.at(
+        &current.position,
+        time_step.time(flight.step_count()),
+        cx.random_mut().wind_seed(),
+    );
+    // Only routes and homing change altitude; otherwise the agent levels off
+    let climb_rate = match phase.control {
+        Control::Route { ref altitude, .. }
+        | Control::Homing { ref altitude, .. }
+        | Control::ToFeeder { ref altitude, .. } => {
+            altitude.climb_rate(current, time_step.time(progress.step), time_step.dt())
+        }
+        _ => 0.0,
+    };
+    let current = &current.climbing(climb_rate);
+    let next = match phase.control {
+        Control::Route {
+            steps,
+            acceleration,
+            vary_speed,
+            ref generator,
+            ..
+        } => {
+            let program = progress.program.get_or_insert_with(|| {
+                let rng = cx.random_mut().route_rng();
+                MotorProgram {
+                    generator: generator.generator(rng, steps, time_step.dt()),
+                    accelerations: movement::generate_accelerations(
+                        rng,
+                        steps,
+                        acceleration,
+                        vary_speed,
+                        time_step.dt(),
+                    ),
+                }
+            });
+            current.advance(
+                program.generator.turn(progress.step, current),
+                program.accelerations[progress.step],
+                DEFAULT_DRAG,
+                wind,
+                &time_step,
+                world,
+            )
+        }
+        Control::Replay { ref states } => states[progress.step].clone(),
+        Control::Pause { .. } | Control::Feed { .. } => {
+            current.advance(0.0, 0.0, DEFAULT_DRAG, wind, &time_step, world)
+        }
+        Control::Displace(ref displacement) => displacement.apply(current),
+        Control::Homing { acceleration, .. } | Control::ToFeeder { acceleration, .. } => {
+            current.advance(motor, acceleration, DEFAULT_DRAG, wind, &time_step, world)
+        }
+        Control::Search {
+            acceleration,
+            turn,
+            growth,
+            ..
+        } => {
+            let rotation = turn / (1.0 + growth * time_step.time(progress.step));
+            current.advance(
+                rotation,
+                acceleration,
+                DEFAULT_DRAG,
+                wind,
+                &time_step,
+                world,
+            )
+        }
+    };
+
+    flight.push(next, progress.phase);
+    let target = match phase.control {
+        Control::ToFeeder { feeder, .. } => feeder
+            .or(flight.feeders.last().copied())
+            .map(|feeder| (feeder, StopReason::ReachedFeeder)),
+        _ => Some((Vector2::zeros(), StopReason::ReachedHome)),
+    };
+    let home_vector = memory.as_ref().filter(|_| memory_stop).map(|memory| {
+        let length = decode_home_vector(memory).magnitude();
+        let initial = *progress.home_vector.get_or_insert(length);
+        if initial > 0.0 {
+            length / initial
+        } else {
+            0.0
+        }
+    });
+    let reason = stop.and_then(|stop| {
+        stop.check(
+            &flight.physical_states,
+            progress.step + 1,
+            home_vector,
+            target,
+        )
+    });
+
+    let memory = memory.filter(|_| phase.record_memory);
+    sink.record(&StepRecord::new(
+        flight.step_count() - 1,
+        &phase.name,
+        flight.physical_states.last().unwrap(),
+        motor,
+        memory,
+    ));
+
+    // The memory belongs to the state the network has just seen
+    if let Some(ref mut memory_record) = flight.memory_record {
+        if let Some(last) = memory_record.last_mut() {
+            *last = memory;
+        }
+        memory_record.push(None);
+    }
+
+    reason
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::experiment::Experiment;
+
+    const SPEC: &str = r#"
+        [setup]
+        outbound_steps = 300
+        inbound_steps = 300
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = true
+
+        [model]
+        variant = "reference"
+    "#;
+
+    #[derive(Default)]
+    struct Steps(Vec<(usize, Vector2<f32>)>);
+
+    impl Sink for Steps {
+        fn record(&mut self, record: &StepRecord) {
+            self.0.push((record.step, record.position));
+        }
+    }
+
+    #[test]
+    fn streaming_keeps_only_latest_steps() {
+        let spec = SPEC.to_owned()
+            + r#"
+            [setup.stop.search]
+            window = 20
+            straightness = 0.2
+        "#;
+        let experiment = Experiment::from_toml(&spec).unwrap().with_seed(3);
+
+        let mut full = Steps::default();
+        let flight = experiment.run_with(&mut full);
+
+        let mut streamed = Steps::default();
+        let streamed_flight = experiment.stream(&mut streamed);
+
+        assert_eq!(full.0, streamed.0);
+        assert_eq!(streamed_flight.step_count(), flight.physical_states.len());
+        assert!(streamed_flight.physical_states.len() < 2 * 21);
+        for (full, streamed) in flight.phases.iter().zip(&streamed_flight.phases) {
+            let (full, streamed) = (full.termination.unwrap(), streamed.termination.unwrap());
+            assert_eq!(full.reason, streamed.reason);
+            assert_eq!(full.steps, streamed.steps);
+        }
+    }
+
+    #[test]
+    fn memory_stop_only_ends_homing() {
+        // Met as soon as it is checked
+        let spec = SPEC.to_owned()
+            + r#"
+            [setup.stop]
+            memory = 2.0
+        "#;
+        let mut experiment = Experiment::from_toml(&spec).unwrap().with_seed(5);
+        experiment.protocol = Some(experiment.setup.with_revisit(experiment.protocol()));
+
+        let flight = experiment.run();
+        let reason = |name: &str| {
+            let phase = flight
+                .phases
+                .iter()
+                .find(|phase| phase.name == name)
+                .unwrap();
+            phase.termination.unwrap().reason
+        };
+        assert_eq!(reason("homing"), StopReason::MemoryExhausted);
+        assert_eq!(reason("revisit"), StopReason::StepLimit);
+    }
+
+    #[test]
+    fn displacement_keeps_altitude() {
+        let spec = SPEC.to_owned()
+            + r#"
+            [setup.outbound_altitude]
+            type = "constant"
+            height = 5.0
+
+            [setup.displacement]
+            capture = "feeder"
+            translation = [50.0, 0.0]
+        "#;
+        let flight = Experiment::from_toml(&spec).unwrap().with_seed(7).run();
+        for step in flight.steps_in("homing") {
+            assert!((flight.physical_states[step].altitude - 5.0).abs() < 1e-4);
+        }
+    }
+}
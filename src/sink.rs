@@ -0,0 +1,197 @@
+//! Per-step output, written while a trial runs instead of after it.
+//!
+//! Each step produces one record: the physical state the agent moved to, together with
+//! the motor output and memory of the network update that moved it there.
+//!
+//! A sink can be given alongside the full flight data, or instead of it with
+//! [`run_protocol_streaming`](crate::protocol::run_protocol_streaming), which keeps
+//! only the latest steps, so that long flights take no more memory than short ones.
+
+use std::io::{self, Write};
+
+use nalgebra::{SVector, Vector2};
+use serde::Serialize;
+
+use crate::{model::constants::N_CPU4, movement::PhysicalState};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StepRecord<'a> {
+    /// Index of the physical state in the flight.
+    pub step: usize,
+    pub phase: &'a str,
+    pub position: Vector2<f32>,
+    /// Velocity relative to the air.
+    pub velocity: Vector2<f32>,
+    pub ground_velocity: Vector2<f32>,
+    pub heading: f32,
+    pub altitude: f32,
+    pub motor: f32,
+    /// Present if memory is recorded in this phase.
+    pub memory: Option<SVector<f32, N_CPU4>>,
+}
+
+impl<'a> StepRecord<'a> {
+    pub fn new(
+        step: usize,
+        phase: &'a str,
+        state: &PhysicalState,
+        motor: f32,
+        memory: Option<SVector<f32, N_CPU4>>,
+    ) -> Self {
+        StepRecord {
+            step,
+            phase,
+            position: state.position,
+            velocity: state.velocity,
+            ground_velocity: state.ground_velocity(),
+            heading: state.heading,
+            altitude: state.altitude,
+            motor,
+            memory,
+        }
+    }
+}
+
+/// Receives every step of a trial as it happens.
+pub trait Sink {
+    fn record(&mut self, record: &StepRecord);
+}
+
+/// Discards all steps.
+impl Sink for () {
+    fn record(&mut self, _: &StepRecord) {}
+}
+
+impl<S: Sink + ?Sized> Sink for &mut S {
+    fn record(&mut self, record: &StepRecord) {
+        (**self).record(record)
+    }
+}
+
+/// Writes one JSON object per step and line.
+///
+/// Write errors do not interrupt the trial; the first one is returned by `finish`.
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Flushes the writer and returns it, or the first write error.
+    pub fn finish(mut self) -> io::Result<W> {
+        finish(&mut self.writer, self.error)?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Sink for JsonLinesSink<W> {
+    fn record(&mut self, record: &StepRecord) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = serde_json::to_writer(&mut self.writer, record)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(self.writer));
+        self.error = result.err();
+    }
+}
+
+/// Writes one CSV row per step, with a header and one column per CPU4 cell.
+/// Memory columns are left empty in steps without recorded memory.
+///
+/// Write errors do not interrupt the trial; the first one is returned by `finish`.
+pub struct CsvSink<W: Write> {
+    writer: W,
+    header_written: bool,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W) -> Self {
+        CsvSink {
+            writer,
+            header_written: false,
+            error: None,
+        }
+    }
+
+    /// Flushes the writer and returns it, or the first write error.
+    pub fn finish(mut self) -> io::Result<W> {
+        finish(&mut self.writer, self.error)?;
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        write!(
+            self.writer,
+            "step,phase,x,y,vx,vy,ground_vx,ground_vy,heading,altitude,motor"
+        )?;
+        for i in 0..N_CPU4 {
+            write!(self.writer, ",memory_{}", i)?;
+        }
+        writeln!(self.writer)
+    }
+
+    fn write_row(&mut self, record: &StepRecord) -> io::Result<()> {
+        if !self.header_written {
+            self.write_header()?;
+            self.header_written = true;
+        }
+
+        write!(
+            self.writer,
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            record.step,
+            csv_field(record.phase),
+            record.position.x,
+            record.position.y,
+            record.velocity.x,
+            record.velocity.y,
+            record.ground_velocity.x,
+            record.ground_velocity.y,
+            record.heading,
+            record.altitude,
+            record.motor
+        )?;
+        match record.memory {
+            Some(ref memory) => {
+                for value in memory.iter() {
+                    write!(self.writer, ",{}", value)?;
+                }
+            }
+            None => write!(self.writer, "{}", ",".repeat(N_CPU4))?,
+        }
+        writeln!(self.writer)
+    }
+}
+
+impl<W: Write> Sink for CsvSink<W> {
+    fn record(&mut self, record: &StepRecord) {
+        if self.error.is_none() {
+            self.error = self.write_row(record).err();
+        }
+    }
+}
+
+fn finish(writer: &mut impl Write, error: Option<io::Error>) -> io::Result<()> {
+    match error {
+        Some(error) => Err(error),
+        None => writer.flush(),
+    }
+}
+
+/// Quotes a field if it contains separators, quotes or line breaks.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
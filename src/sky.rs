@@ -0,0 +1,373 @@
+//! Skylight polarization compass: the polarization pattern of the sky, and the
+//! polarization-sensitive units of the dorsal rim that read a heading from it.
+//!
+//! The sky scatters sunlight as in single Rayleigh scattering, so the light from each point
+//! is polarized across the plane through the sun and the point, the more so the further the
+//! point is from the sun. The units look up all round the agent, each with opponent analyzers;
+//! their population vector gives the axis of the solar meridian, and the sky being brighter
+//! towards the sun tells which end of it the sun is at. As in a spec:
+//!
+//! ```toml
+//! [setup.senses.compass]
+//! type = "skylight"
+//! sun = { type = "fixed", azimuth = 1.0, elevation = 0.5 }
+//! noise = 0.1
+//! cloud_cover = 0.3
+//! ```
+//!
+//! Over a long flight the sun moves along its daily path, with the time of day running on
+//! the simulation clock. The heading is read from the sun's azimuth as the agent takes it to be,
+//! which without compensation is where the sun was at the start of the flight:
+//!
+//! ```toml
+//! [setup.senses.compass]
+//! type = "skylight"
+//! sun = { type = "daily", latitude = 0.6, start = 10.0, hours_per_time = 0.01 }
+//! compensation = { type = "linear", rate = 0.26 }
+//! ```
+
+use std::fmt;
+
+use nalgebra::{Vector2, Vector3};
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
+use serde::{Deserialize, Serialize};
+
+/// Where the sun is, with the azimuth clockwise from the y axis, which points north, like headings.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sun {
+    Fixed {
+        azimuth: f32,
+        elevation: f32,
+    },
+    /// Moves along its daily path.
+    Daily(SolarDay),
+}
+
+impl Sun {
+    /// Azimuth and elevation at `time` since the start of the flight.
+    pub fn position(&self, time: f32) -> (f32, f32) {
+        match *self {
+            Sun::Fixed { azimuth, elevation } => (azimuth, elevation),
+            Sun::Daily(ref day) => day.position(time),
+        }
+    }
+}
+
+/// The sun's path through the sky over a day, and the time of day on the simulation clock.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SolarDay {
+    /// Latitude of the nest; negative in the southern hemisphere.
+    pub latitude: f32,
+    /// Declination of the sun, from the time of year; 0 at the equinoxes.
+    #[serde(default)]
+    pub declination: f32,
+    /// Hour of the day at the start of the flight.
+    pub start: f32,
+    /// Hours of the day per unit of simulation time.
+    pub hours_per_time: f32,
+}
+
+impl SolarDay {
+    pub fn hour(&self, time: f32) -> f32 {
+        self.start + time * self.hours_per_time
+    }
+
+    pub fn position(&self, time: f32) -> (f32, f32) {
+        let hour_angle = (self.hour(time) - 12.0) * std::f32::consts::PI / 12.0;
+        let (latitude, declination) = (self.latitude, self.declination);
+        // East, north and up
+        let sun = Vector3::new(
+            -declination.cos() * hour_angle.sin(),
+            declination.sin() * latitude.cos()
+                - declination.cos() * latitude.sin() * hour_angle.cos(),
+            declination.sin() * latitude.sin()
+                + declination.cos() * latitude.cos() * hour_angle.cos(),
+        );
+        (sun.x.atan2(sun.y), sun.z.clamp(-1.0, 1.0).asin())
+    }
+}
+
+/// How the agent allows for the sun moving while it reads its heading from the sun's azimuth.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Compensation {
+    /// Takes the sun to stay where it was at the start of the flight.
+    #[default]
+    None,
+    /// Takes the sun to turn clockwise at a constant `rate` in radians per hour,
+    /// e.g. about 0.26 for 15° per hour.
+    Linear { rate: f32 },
+    /// Knows exactly where the sun is.
+    Ephemeris,
+}
+
+impl Compensation {
+    /// The sun's azimuth at `time` as the agent takes it to be.
+    pub fn azimuth(&self, sun: &Sun, time: f32) -> f32 {
+        match (self, sun) {
+            (Compensation::None, _) => sun.position(0.0).0,
+            (Compensation::Linear { rate }, Sun::Daily(day)) => {
+                sun.position(0.0).0 + rate * (day.hour(time) - day.start)
+            }
+            (Compensation::Linear { .. }, Sun::Fixed { .. }) => sun.position(0.0).0,
+            (Compensation::Ephemeris, _) => sun.position(time).0,
+        }
+    }
+}
+
+fn direction(azimuth: f32, elevation: f32) -> Vector3<f32> {
+    Vector3::new(
+        azimuth.sin() * elevation.cos(),
+        azimuth.cos() * elevation.cos(),
+        elevation.sin(),
+    )
+}
+
+/// Degree of polarization of the light from `view`, and its E-vector.
+pub fn polarization(
+    sun: &Vector3<f32>,
+    view: &Vector3<f32>,
+    max_degree: f32,
+) -> (f32, Vector3<f32>) {
+    let cos_distance = sun.dot(view).clamp(-1.0, 1.0).powi(2);
+    let degree = max_degree * (1.0 - cos_distance) / (1.0 + cos_distance);
+    let e_vector = sun.cross(view);
+    match e_vector.try_normalize(f32::EPSILON) {
+        Some(e_vector) => (degree, e_vector),
+        None => (0.0, Vector3::zeros()),
+    }
+}
+
+/// Zero-mean normal noise, read and written as its standard deviation,
+/// which must be finite and not negative.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "f32", into = "f32")]
+pub struct NoiseLevel(Normal<f32>);
+
+#[derive(Debug)]
+pub struct InvalidNoise(pub f32);
+
+impl fmt::Display for InvalidNoise {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "noise must be a finite, non-negative standard deviation, got {}",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidNoise {}
+
+impl NoiseLevel {
+    pub fn new(std_dev: f32) -> Result<NoiseLevel, InvalidNoise> {
+        match Normal::new(0.0, std_dev) {
+            Ok(normal) if std_dev >= 0.0 => Ok(NoiseLevel(normal)),
+            _ => Err(InvalidNoise(std_dev)),
+        }
+    }
+
+    pub fn std_dev(&self) -> f32 {
+        self.0.std_dev()
+    }
+}
+
+impl Default for NoiseLevel {
+    fn default() -> Self {
+        NoiseLevel::new(0.0).unwrap()
+    }
+}
+
+impl TryFrom<f32> for NoiseLevel {
+    type Error = InvalidNoise;
+
+    fn try_from(std_dev: f32) -> Result<Self, Self::Error> {
+        NoiseLevel::new(std_dev)
+    }
+}
+
+impl From<NoiseLevel> for f32 {
+    fn from(noise: NoiseLevel) -> f32 {
+        noise.std_dev()
+    }
+}
+
+impl std::str::FromStr for NoiseLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let std_dev = s.parse::<f32>().map_err(|e| e.to_string())?;
+        NoiseLevel::new(std_dev).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Skylight {
+    pub sun: Sun,
+    /// Number of polarization units, evenly spaced round the agent.
+    #[serde(default = "default_units")]
+    pub units: usize,
+    /// Elevation at which the units look at the sky.
+    #[serde(default = "default_view_elevation")]
+    pub view_elevation: f32,
+    /// Degree of polarization at 90° from the sun.
+    #[serde(default = "default_max_degree")]
+    pub max_degree: f32,
+    /// Standard deviation of the noise in the degree of polarization each unit senses.
+    #[serde(default)]
+    pub noise: NoiseLevel,
+    /// Chance that a unit's view of the sky is behind cloud, which does not polarize light.
+    #[serde(default)]
+    pub cloud_cover: f32,
+    #[serde(default)]
+    pub compensation: Compensation,
+}
+
+fn default_units() -> usize {
+    16
+}
+
+fn default_view_elevation() -> f32 {
+    std::f32::consts::FRAC_PI_3
+}
+
+fn default_max_degree() -> f32 {
+    0.75
+}
+
+impl Skylight {
+    /// The heading as read from the sky when facing `heading` at `time` since the start of the
+    /// flight, or `None` if no unit senses polarized light.
+    pub fn heading(&self, heading: f32, time: f32, rng: &mut impl Rng) -> Option<f32> {
+        let (azimuth, elevation) = self.sun.position(time);
+        let sun = direction(azimuth, elevation);
+
+        // Population vectors, at twice the unit angles for the axial polarization
+        let mut polarization = Vector2::zeros();
+        let mut brightness = Vector2::zeros();
+        for i in 0..self.units {
+            let offset = std::f32::consts::TAU * i as f32 / self.units as f32;
+            let azimuth = heading + offset;
+            let view = direction(azimuth, self.view_elevation);
+            let clouded = rng.gen::<f32>() < self.cloud_cover;
+            let noise = self.noise.0.sample(rng);
+            if clouded {
+                continue;
+            }
+
+            let (degree, e_vector) = self::polarization(&sun, &view, self.max_degree);
+            let degree = (degree + noise).clamp(0.0, 1.0);
+            // Opponent analyzers along and across the horizontal at right angles to the view
+            let analyzer = Vector3::new(azimuth.cos(), -azimuth.sin(), 0.0);
+            let response = degree * (2.0 * e_vector.dot(&analyzer).powi(2) - 1.0);
+            polarization += Vector2::new((2.0 * offset).sin(), (2.0 * offset).cos()) * response;
+            brightness += Vector2::new(offset.sin(), offset.cos()) * sun.dot(&view);
+        }
+        if polarization.magnitude() <= f32::EPSILON {
+            return None;
+        }
+
+        // The solar meridian relative to the heading, on the brighter side
+        let mut meridian = polarization.x.atan2(polarization.y) / 2.0;
+        if (meridian - brightness.x.atan2(brightness.y)).cos() < 0.0 {
+            meridian += std::f32::consts::PI;
+        }
+        Some(self.compensation.azimuth(&self.sun, time) - meridian)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode::wrap_angle, experiment::Experiment, stats::FlightStats};
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn rejects_invalid_noise() {
+        let spec = |noise: f32| {
+            format!(
+                r#"{{ "sun": {{ "type": "fixed", "azimuth": 1.0, "elevation": 0.5 }}, "noise": {} }}"#,
+                noise
+            )
+        };
+        let skylight: Skylight = serde_json::from_str(&spec(0.1)).unwrap();
+        assert_eq!(skylight.noise.std_dev(), 0.1);
+        assert!(serde_json::from_str::<Skylight>(&spec(-0.1)).is_err());
+        assert!("NaN".parse::<NoiseLevel>().is_err());
+        assert!("inf".parse::<NoiseLevel>().is_err());
+    }
+
+    fn daily_skylight(compensation: &str) -> Skylight {
+        let spec = format!(
+            r#"
+            sun = {{ type = "daily", latitude = 0.6, start = 8.0, hours_per_time = 0.01 }}
+            compensation = {compensation}
+            "#
+        );
+        toml::from_str(&spec).unwrap()
+    }
+
+    #[test]
+    fn compensation_keeps_the_heading_over_a_day() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        let heading = 2.0;
+        // From 8 in the morning to 4 in the afternoon
+        let times = (0..=8).map(|hour| hour as f32 * 100.0);
+        let mut error = |skylight: &Skylight, time: f32| {
+            wrap_angle(skylight.heading(heading, time, &mut rng).unwrap() - heading)
+        };
+
+        let (fixed, exact, linear) = (
+            daily_skylight(r#"{ type = "none" }"#),
+            daily_skylight(r#"{ type = "ephemeris" }"#),
+            daily_skylight(r#"{ type = "linear", rate = 0.26 }"#),
+        );
+        for time in times {
+            // Off by however far the sun has moved
+            let moved = fixed.sun.position(time).0 - fixed.sun.position(0.0).0;
+            assert!((error(&fixed, time) + wrap_angle(moved)).abs() < 1e-3);
+            assert!(error(&exact, time).abs() < 1e-3);
+        }
+        assert!(error(&fixed, 800.0).abs() > 2.0);
+        assert!(error(&linear, 800.0).abs() < 0.5);
+    }
+
+    /// How close the agent gets to the nest after a trip of six hours.
+    fn closest_to_home(compensation: &str) -> f32 {
+        let spec = format!(
+            r#"
+            seed = 2
+
+            [setup]
+            outbound_steps = 1500
+            inbound_steps = 1500
+            acceleration_out = 0.15
+            acceleration_in = 0.1
+            vary_speed = true
+            record_memory = false
+
+            [setup.senses.compass]
+            type = "skylight"
+            sun = {{ type = "daily", latitude = 0.6, start = 9.0, hours_per_time = 0.004 }}
+            compensation = {compensation}
+
+            [model]
+            variant = "reference"
+            "#
+        );
+        let flight = Experiment::from_toml(&spec).unwrap().run();
+        FlightStats::analyze(&flight).min_distance_to_home.unwrap()
+    }
+
+    #[test]
+    fn compensation_brings_the_agent_home_after_a_long_trip() {
+        let exact = closest_to_home(r#"{ type = "ephemeris" }"#);
+        let linear = closest_to_home(r#"{ type = "linear", rate = 0.26 }"#);
+        let fixed = closest_to_home(r#"{ type = "none" }"#);
+        assert!(exact < 10.0);
+        assert!(exact < linear && linear < fixed);
+    }
+}
@@ -1,23 +1,516 @@
-use crate::{movement::reconstruct_path, FlightData, Setup};
+use std::collections::BTreeMap;
 
-#[derive(Default, Debug)]
+use nalgebra::Vector2;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    decode::{compass_angle, wrap_angle},
+    protocol::{ControlKind, Termination},
+    util::derive_seed,
+    FlightData,
+};
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct FlightStats {
-    pub min_distance_to_home: f32,
+    /// Closest the agent came to the nest while homing; absent if it did not home.
+    #[serde(default)]
+    pub min_distance_to_home: Option<f32>,
+    /// How the last homing phase ended and how many steps it took.
+    #[serde(default)]
+    pub homing: Option<Termination>,
+    /// Path metrics of the last homing phase.
+    #[serde(default)]
+    pub homing_path: Option<HomingStats>,
+    /// Present if the agent was displaced before homing.
+    #[serde(default)]
+    pub release: Option<ReleaseStats>,
+}
+
+/// Distance from the start of homing at which the initial homing direction is measured.
+pub const INITIAL_HEADING_DISTANCE: f32 = 20.0;
+
+/// How the agent makes its way back, relative to where it started homing.
+///
+/// The agent turns back at the first step that takes it backwards along the home vector it started
+/// with, once it has got further along the home vector than its start: steps before that only
+/// carry the momentum of the outbound route. That is where its run ends and its search begins.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HomingStats {
+    pub start: Vector2<f32>,
+    /// Steps into the phase until the agent came closest to the nest.
+    pub closest_approach_step: usize,
+    pub closest_approach_distance: f32,
+    /// Signed compass angle, clockwise, from the true home vector to where the agent first got
+    /// `INITIAL_HEADING_DISTANCE` away from its start; absent if it started at the nest or never got that far.
+    pub initial_heading_error: Option<f32>,
+    /// Path length up to the first turn-back, if the agent turned back.
+    pub distance_before_turn_back: Option<f32>,
+    /// How far past the nest, along the home vector, the agent turned back;
+    /// negative if it turned back short of the nest.
+    pub overshoot: Option<f32>,
+    /// Beeline distance over path length, up to the closest approach; absent if the agent did not move.
+    pub straightness: Option<f32>,
+    /// Path length over beeline distance, up to the closest approach; absent if the agent did not move.
+    pub tortuosity: Option<f32>,
+    /// Mean distance from the nest after the first turn-back.
+    pub search_radius: Option<f32>,
+}
+
+/// How well a displaced agent runs off its stored home vector from the release point.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReleaseStats {
+    pub capture_point: Vector2<f32>,
+    pub release_point: Vector2<f32>,
+    /// Where the home vector held at capture leads to when run off from the release point.
+    pub fictive_home: Vector2<f32>,
+    /// Absent if the agent did not home after its release.
+    pub min_distance_to_fictive_home: Option<f32>,
+    pub min_distance_to_home: Option<f32>,
 }
 
 impl FlightStats {
-    pub fn analyze(setup: &Setup, result: &FlightData) -> FlightStats {
-        let mut stats = FlightStats::default();
-        stats.min_distance_to_home = f32::INFINITY;
-
-        let path = reconstruct_path(&result.physical_states);
-        for position in &path[(setup.outbound_steps + 1)..] {
-            let distance = position.magnitude();
-            if distance < stats.min_distance_to_home {
-                stats.min_distance_to_home = distance;
+    pub fn analyze(result: &FlightData) -> FlightStats {
+        let mut stats = FlightStats {
+            min_distance_to_home: result
+                .steps_with(ControlKind::Homing)
+                .map(|step| result.physical_states[step].position.magnitude())
+                .min_by(f32::total_cmp),
+            homing: None,
+            homing_path: None,
+            release: None,
+        };
+
+        stats.homing = result
+            .phases
+            .iter()
+            .rev()
+            .filter(|phase| phase.control == ControlKind::Homing)
+            .find_map(|phase| phase.termination);
+        stats.homing_path = HomingStats::analyze(result);
+        stats.release = ReleaseStats::analyze(result);
+
+        stats
+    }
+}
+
+impl HomingStats {
+    /// Analyzes the last homing phase in the flight, if any.
+    pub fn analyze(result: &FlightData) -> Option<HomingStats> {
+        let phase = result
+            .phases
+            .iter()
+            .rposition(|phase| phase.control == ControlKind::Homing)?;
+        let steps: Vec<usize> = result
+            .step_phases
+            .iter()
+            .enumerate()
+            .filter(|&(_, &p)| p == phase)
+            .map(|(step, _)| step)
+            .collect();
+        let first = *steps.first()?;
+
+        let start = result.before(first).position;
+        let path: Vec<Vector2<f32>> = std::iter::once(start)
+            .chain(
+                steps
+                    .iter()
+                    .map(|&step| result.physical_states[step].position),
+            )
+            .collect();
+        let lengths: Vec<f32> = std::iter::once(0.0)
+            .chain(path.windows(2).scan(0.0, |length, pair| {
+                *length += (pair[1] - pair[0]).magnitude();
+                Some(*length)
+            }))
+            .collect();
+
+        let (closest_approach_step, closest_approach_distance) = path
+            .iter()
+            .map(|position| position.magnitude())
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))?;
+
+        let home = (start.magnitude() > 0.0).then(|| -start.normalize());
+
+        let initial_heading_error = home.and_then(|home| {
+            let direction = path
+                .iter()
+                .map(|position| position - start)
+                .find(|direction| direction.magnitude() >= INITIAL_HEADING_DISTANCE)?;
+            Some(wrap_angle(compass_angle(&direction) - compass_angle(&home)))
+        });
+
+        let turn_back = home.and_then(|home| {
+            path.windows(2)
+                .enumerate()
+                .skip_while(|(_, pair)| (pair[0] - start).dot(&home) <= 0.0)
+                .find(|(_, pair)| (pair[1] - pair[0]).dot(&home) < 0.0)
+                .map(|(turn, _)| turn)
+        });
+
+        let beeline = (path[closest_approach_step] - start).magnitude();
+        let length = lengths[closest_approach_step];
+
+        Some(HomingStats {
+            start,
+            closest_approach_step,
+            closest_approach_distance,
+            initial_heading_error,
+            distance_before_turn_back: turn_back.map(|turn| lengths[turn]),
+            overshoot: turn_back
+                .zip(home)
+                .map(|(turn, home)| path[turn].dot(&home)),
+            straightness: (length > 0.0).then(|| beeline / length),
+            tortuosity: (beeline > 0.0).then(|| length / beeline),
+            search_radius: turn_back.map(|turn| {
+                let search = &path[turn..];
+                search
+                    .iter()
+                    .map(|position| position.magnitude())
+                    .sum::<f32>()
+                    / search.len() as f32
+            }),
+        })
+    }
+}
+
+impl ReleaseStats {
+    /// Analyzes homing after the last displacement in the flight, if any.
+    pub fn analyze(result: &FlightData) -> Option<ReleaseStats> {
+        let release = result.steps_with(ControlKind::Displace).last()?;
+        let states = &result.physical_states;
+
+        let capture_point = result.before(release).position;
+        let release_point = states[release].position;
+        let fictive_home = release_point - capture_point;
+
+        let homing: Vec<Vector2<f32>> = result
+            .steps_with(ControlKind::Homing)
+            .filter(|&step| step > release)
+            .map(|step| states[step].position)
+            .collect();
+        let min_distance = |goal: Vector2<f32>| {
+            homing
+                .iter()
+                .map(|position| (position - goal).magnitude())
+                .min_by(f32::total_cmp)
+        };
+
+        Some(ReleaseStats {
+            capture_point,
+            release_point,
+            fictive_home,
+            min_distance_to_fictive_home: min_distance(fictive_home),
+            min_distance_to_home: min_distance(Vector2::zeros()),
+        })
+    }
+}
+
+/// Bootstrap settings for confidence intervals of the mean.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Bootstrap {
+    pub resamples: usize,
+    pub confidence: f32,
+}
+
+impl Default for Bootstrap {
+    fn default() -> Self {
+        Bootstrap {
+            resamples: 1000,
+            confidence: 0.95,
+        }
+    }
+}
+
+/// Distribution of one metric across trials.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    /// Number of trials in which the metric was present and finite.
+    pub n: usize,
+    pub mean: f32,
+    pub std_dev: f32,
+    pub standard_error: f32,
+    pub min: f32,
+    pub p5: f32,
+    pub p25: f32,
+    pub median: f32,
+    pub p75: f32,
+    pub p95: f32,
+    pub max: f32,
+    /// Percentile bootstrap confidence interval of the mean.
+    pub confidence_interval: (f32, f32),
+}
+
+impl Summary {
+    /// Summarizes the given values, or returns `None` if there are none.
+    pub fn new(values: &[f32], bootstrap: &Bootstrap, seed: u64) -> Option<Summary> {
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f32::total_cmp);
+        let n = sorted.len();
+        if n == 0 {
+            return None;
+        }
+
+        let mean = mean(&sorted);
+        let variance = match n {
+            1 => 0.0,
+            _ => sorted.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / (n - 1) as f32,
+        };
+        let std_dev = variance.sqrt();
+
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+        let mut means: Vec<f32> = (0..bootstrap.resamples)
+            .map(|_| (0..n).map(|_| sorted[rng.gen_range(0..n)]).sum::<f32>() / n as f32)
+            .collect();
+        means.sort_by(f32::total_cmp);
+        let alpha = 1.0 - bootstrap.confidence;
+        let confidence_interval = if means.is_empty() {
+            (mean, mean)
+        } else {
+            (
+                percentile(&means, alpha / 2.0),
+                percentile(&means, 1.0 - alpha / 2.0),
+            )
+        };
+
+        Some(Summary {
+            n,
+            mean,
+            std_dev,
+            standard_error: std_dev / (n as f32).sqrt(),
+            min: sorted[0],
+            p5: percentile(&sorted, 0.05),
+            p25: percentile(&sorted, 0.25),
+            median: percentile(&sorted, 0.5),
+            p75: percentile(&sorted, 0.75),
+            p95: percentile(&sorted, 0.95),
+            max: sorted[n - 1],
+            confidence_interval,
+        })
+    }
+}
+
+/// Summaries of every numeric metric across many trials.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub trials: usize,
+    /// Keyed by the metric's path in `FlightStats`, e.g. `homing_path.straightness`.
+    pub metrics: BTreeMap<String, Summary>,
+}
+
+impl Aggregate {
+    /// Bootstrap resampling is seeded from `seed`, so the result is reproducible.
+    pub fn new<'a>(
+        stats: impl IntoIterator<Item = &'a FlightStats>,
+        bootstrap: &Bootstrap,
+        seed: u64,
+    ) -> Aggregate {
+        let mut trials = 0;
+        let mut values: BTreeMap<String, Vec<f32>> = BTreeMap::new();
+        for stats in stats {
+            trials += 1;
+            for (metric, value) in stats.metrics() {
+                if value.is_finite() {
+                    values.entry(metric).or_default().push(value);
+                }
             }
         }
 
-        stats
+        let metrics = values
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, (metric, values))| {
+                let summary = Summary::new(&values, bootstrap, derive_seed(seed, &[i as u64]))?;
+                Some((metric, summary))
+            })
+            .collect();
+
+        Aggregate { trials, metrics }
+    }
+}
+
+impl FlightStats {
+    /// All numeric metrics, keyed by their path, with vector components indexed,
+    /// e.g. `release.fictive_home.0`. Metrics absent from this flight are left out.
+    pub fn metrics(&self) -> BTreeMap<String, f32> {
+        let mut metrics = BTreeMap::new();
+        let value = serde_json::to_value(self).expect("flight stats are serializable");
+        collect_metrics(&value, String::new(), &mut metrics);
+        metrics
+    }
+}
+
+fn collect_metrics(value: &Value, path: String, metrics: &mut BTreeMap<String, f32>) {
+    let child = |key: &dyn std::fmt::Display| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match value {
+        Value::Number(number) => {
+            if let Some(number) = number.as_f64() {
+                metrics.insert(path, number as f32);
+            }
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                collect_metrics(value, child(&i), metrics);
+            }
+        }
+        Value::Object(values) => {
+            for (key, value) in values {
+                collect_metrics(value, child(key), metrics);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::String(_) => {}
+    }
+}
+
+fn mean(values: &[f32]) -> f32 {
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+/// Linearly interpolated percentile of sorted values, with `q` in [0, 1].
+fn percentile(sorted: &[f32], q: f32) -> f32 {
+    let position = q.clamp(0.0, 1.0) * (sorted.len() - 1) as f32;
+    let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (position - lower as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        decode::{column_direction, HeadingDecoding},
+        experiment::Experiment,
+        model::constants::N_TB1,
+        movement::PhysicalState,
+        probe::{Population, ProbeRecord},
+        protocol::Control,
+    };
+
+    /// An outbound route and no homing.
+    const SPEC: &str = r#"
+        seed = 4
+
+        [setup]
+        outbound_steps = 300
+        inbound_steps = 0
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = true
+
+        [model]
+        variant = "reference"
+    "#;
+
+    #[test]
+    fn flight_without_homing_round_trips() {
+        let stats = FlightStats::analyze(&Experiment::from_toml(SPEC).unwrap().run());
+        assert_eq!(stats.min_distance_to_home, None);
+
+        let json = serde_json::to_string(&stats).unwrap();
+        let stats: FlightStats = serde_json::from_str(&json).unwrap();
+        assert_eq!(stats.min_distance_to_home, None);
+        assert!(!stats.metrics().contains_key("min_distance_to_home"));
+
+        let aggregate = Aggregate::new([&stats], &Bootstrap::default(), 0);
+        assert_eq!(aggregate.trials, 1);
+        assert!(!aggregate.metrics.contains_key("min_distance_to_home"));
+    }
+
+    #[test]
+    fn initial_heading_error_has_the_sign_of_the_heading_error() {
+        // Starting north of the nest and homing clockwise of due south, while the
+        // compass reads due south
+        let setup = Experiment::from_toml(SPEC).unwrap().setup;
+        let homing = Control::Homing {
+            steps: 30,
+            acceleration: 0.1,
+            stop: Default::default(),
+            altitude: Default::default(),
+        };
+        let mut flight = FlightData::new(&setup, &[setup.phase("homing", homing)]);
+        let (south, heading) = (std::f32::consts::PI, std::f32::consts::PI + 0.2);
+        let start = Vector2::new(0.0, 100.0);
+        flight.initial.position = start;
+        for step in 1..=30 {
+            flight.physical_states.push(PhysicalState {
+                position: start + Vector2::new(heading.sin(), heading.cos()) * step as f32,
+                heading: south,
+                ..Default::default()
+            });
+            flight.step_phases.push(0);
+        }
+
+        // TB1 is least active facing the heading
+        let tb1 = (0..N_TB1)
+            .map(|column| 1.0 - (column_direction(column) - heading).cos())
+            .collect();
+        let mut probes = ProbeRecord::default();
+        probes.steps.push(0);
+        probes.activities.insert(Population::Tb1, vec![tb1]);
+        flight.probe_record = Some(probes);
+
+        let decoded = HeadingDecoding::analyze(&flight).unwrap().steps[0].error;
+        let error = HomingStats::analyze(&flight)
+            .unwrap()
+            .initial_heading_error
+            .unwrap();
+        assert!(decoded > 0.0);
+        assert!((error - decoded).abs() < 1e-3);
+    }
+
+    #[test]
+    fn displaced_agents_run_off_their_vector_from_the_release_point() {
+        let spec = SPEC.replace("inbound_steps = 0", "inbound_steps = 600")
+            + r#"
+            [setup.displacement]
+            capture = "feeder"
+            release_point = [200.0, -150.0]
+        "#;
+        let flight = Experiment::from_toml(&spec).unwrap().run();
+        let release = ReleaseStats::analyze(&flight).unwrap();
+
+        let outbound_end = &flight.physical_states[flight.steps_in("outbound").last().unwrap()];
+        assert_eq!(release.capture_point, outbound_end.position);
+        assert_eq!(release.release_point, Vector2::new(200.0, -150.0));
+        assert_eq!(
+            release.fictive_home,
+            release.release_point - release.capture_point
+        );
+        let to_fictive_home = release.min_distance_to_fictive_home.unwrap();
+        assert!(to_fictive_home < 0.2 * release.fictive_home.magnitude());
+        assert!(to_fictive_home < release.min_distance_to_home.unwrap());
+    }
+
+    #[test]
+    fn summaries_describe_the_values() {
+        let values = [7.0, 2.0, 10.0, 4.0, 1.0, 9.0, 3.0, 6.0, 8.0, 5.0];
+        let summary = Summary::new(&values, &Bootstrap::default(), 3).unwrap();
+        assert_eq!(summary.n, 10);
+        assert_eq!((summary.min, summary.max), (1.0, 10.0));
+        assert_eq!(summary.mean, 5.5);
+        assert_eq!(summary.median, 5.5);
+        assert_eq!((summary.p25, summary.p75), (3.25, 7.75));
+        assert!((summary.std_dev - (55.0f32 / 6.0).sqrt()).abs() < 1e-5);
+        assert!((summary.standard_error - summary.std_dev / 10.0f32.sqrt()).abs() < 1e-6);
+
+        // Roughly two standard errors either side of the mean
+        let (low, high) = summary.confidence_interval;
+        assert!(low < summary.mean && summary.mean < high);
+        assert!(low > summary.p25 && high < summary.p75);
+        assert!((high - low - 4.0 * summary.standard_error).abs() < summary.standard_error);
+
+        let again = Summary::new(&values, &Bootstrap::default(), 3).unwrap();
+        assert_eq!(again.confidence_interval, summary.confidence_interval);
+        assert!(Summary::new(&[], &Bootstrap::default(), 3).is_none());
     }
 }
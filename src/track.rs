@@ -0,0 +1,305 @@
+//! Recorded trajectories of animals or robots, for replaying as outbound routes.
+//!
+//! Tracks are read from CSV files with a header naming the columns, either positions:
+//!
+//! ```text
+//! t,x,y
+//! 0.0,0.0,0.0
+//! 0.5,0.1,1.2
+//! ```
+//!
+//! or motion, with headings in radians as compass angles (0 along +y, increasing towards +x):
+//!
+//! ```text
+//! t,heading,speed
+//! 0.0,0.0,2.4
+//! 0.5,0.1,2.5
+//! ```
+//!
+//! Other columns are ignored, whatever they hold. The track is taken to start at the nest.
+
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+use nalgebra::Vector2;
+
+use crate::{decode::wrap_angle, movement::PhysicalState};
+
+#[derive(Debug)]
+pub enum TrackError {
+    Io(std::io::Error),
+    /// Header line without either `x` and `y` or `heading` and `speed` columns, or without `t`.
+    MissingColumns,
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// Fewer than two samples, or too short to resample at the requested step.
+    TooShort,
+}
+
+impl fmt::Display for TrackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackError::Io(e) => write!(f, "could not read track: {}", e),
+            TrackError::MissingColumns => write!(
+                f,
+                "track header needs a t column and either x and y or heading and speed columns"
+            ),
+            TrackError::Parse { line, message } => {
+                write!(f, "invalid track at line {}: {}", line, message)
+            }
+            TrackError::TooShort => write!(f, "track is too short to resample"),
+        }
+    }
+}
+
+impl std::error::Error for TrackError {}
+
+/// A track as positions over time, with the recorded headings if there were any.
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub times: Vec<f32>,
+    pub positions: Vec<Vector2<f32>>,
+    pub headings: Option<Vec<f32>>,
+}
+
+enum Columns {
+    Position {
+        t: usize,
+        x: usize,
+        y: usize,
+    },
+    Motion {
+        t: usize,
+        heading: usize,
+        speed: usize,
+    },
+}
+
+impl Track {
+    pub fn load(path: impl AsRef<Path>) -> Result<Track, TrackError> {
+        let file = std::fs::File::open(path).map_err(TrackError::Io)?;
+        Self::read_csv(file)
+    }
+
+    pub fn read_csv(reader: impl Read) -> Result<Track, TrackError> {
+        let mut lines = BufReader::new(reader)
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line))
+            .filter(|(_, line)| {
+                line.as_ref().map_or(true, |line| {
+                    !line.trim().is_empty() && !line.starts_with('#')
+                })
+            });
+
+        let header = match lines.next() {
+            Some((_, line)) => line.map_err(TrackError::Io)?,
+            None => return Err(TrackError::TooShort),
+        };
+        let names: Vec<String> = header
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .collect();
+        let column = |name: &str| names.iter().position(|n| n == name);
+        let columns = match (
+            column("t"),
+            column("x"),
+            column("y"),
+            column("heading"),
+            column("speed"),
+        ) {
+            (Some(t), Some(x), Some(y), _, _) => Columns::Position { t, x, y },
+            (Some(t), _, _, Some(heading), Some(speed)) => Columns::Motion { t, heading, speed },
+            _ => return Err(TrackError::MissingColumns),
+        };
+
+        // Only the columns in use need to be numbers
+        let used = match columns {
+            Columns::Position { t, x, y } => [t, x, y],
+            Columns::Motion { t, heading, speed } => [t, heading, speed],
+        };
+        let mut rows: Vec<[f32; 3]> = Vec::new();
+        for (line_number, line) in lines {
+            let line = line.map_err(TrackError::Io)?;
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() < names.len() {
+                return Err(TrackError::Parse {
+                    line: line_number,
+                    message: format!("expected {} values, got {}", names.len(), fields.len()),
+                });
+            }
+            let mut row = [0.0; 3];
+            for (value, &column) in row.iter_mut().zip(&used) {
+                *value = fields[column]
+                    .parse::<f32>()
+                    .map_err(|e| TrackError::Parse {
+                        line: line_number,
+                        message: format!("{}: {}", names[column], e),
+                    })?;
+            }
+            if rows.last().is_some_and(|previous| row[0] <= previous[0]) {
+                return Err(TrackError::Parse {
+                    line: line_number,
+                    message: "times must be strictly increasing".into(),
+                });
+            }
+            rows.push(row);
+        }
+        if rows.len() < 2 {
+            return Err(TrackError::TooShort);
+        }
+
+        let times = rows.iter().map(|&[t, _, _]| t).collect();
+        Ok(match columns {
+            Columns::Position { .. } => Track {
+                times,
+                positions: rows.iter().map(|&[_, x, y]| Vector2::new(x, y)).collect(),
+                headings: None,
+            },
+            Columns::Motion { .. } => {
+                // Dead reckoning, moving at each sample's speed and heading until the next
+                let mut positions = vec![Vector2::zeros()];
+                for pair in rows.windows(2) {
+                    let ([t, heading, speed], [next, _, _]) = (pair[0], pair[1]);
+                    let direction = Vector2::new(heading.sin(), heading.cos());
+                    let last = positions[positions.len() - 1];
+                    positions.push(last + direction * speed * (next - t));
+                }
+                Track {
+                    times,
+                    positions,
+                    headings: Some(rows.iter().map(|&[_, heading, _]| heading).collect()),
+                }
+            }
+        })
+    }
+
+    /// Resamples the track every `dt` time units, scaling distances by `scale`,
+    /// into physical states starting from the nest. `dt` should be the simulation's time step,
+    /// since velocities are taken as rates per unit time.
+    ///
+    /// Headings are interpolated from the recording if it has them,
+    /// and otherwise follow the direction of movement.
+    pub fn resample(&self, dt: f32, scale: f32) -> Result<Vec<PhysicalState>, TrackError> {
+        if dt.is_nan() || dt <= 0.0 {
+            return Err(TrackError::TooShort);
+        }
+        let start = self.times[0];
+        let duration = self.times[self.times.len() - 1] - start;
+        let steps = (duration / dt).floor() as usize;
+        if steps == 0 {
+            return Err(TrackError::TooShort);
+        }
+
+        let origin = self.positions[0];
+        let mut states: Vec<PhysicalState> = Vec::with_capacity(steps);
+        let mut segment = 0;
+        let mut previous = PhysicalState::default();
+        for step in 1..=steps {
+            let t = start + step as f32 * dt;
+            while segment + 2 < self.times.len() && self.times[segment + 1] < t {
+                segment += 1;
+            }
+            let (t0, t1) = (self.times[segment], self.times[segment + 1]);
+            let factor = ((t - t0) / (t1 - t0)).clamp(0.0, 1.0);
+
+            let (p0, p1) = (self.positions[segment], self.positions[segment + 1]);
+            let position = (p0 + (p1 - p0) * factor - origin) * scale;
+            let velocity = (position - previous.position) / dt;
+            let heading = match self.headings {
+                Some(ref headings) => {
+                    let (h0, h1) = (headings[segment], headings[segment + 1]);
+                    h0 + wrap_angle(h1 - h0) * factor
+                }
+                None if velocity.magnitude() > 0.0 => velocity.x.atan2(velocity.y),
+                None => previous.heading,
+            };
+
+            previous = PhysicalState {
+                position,
+                velocity,
+                heading: heading.rem_euclid(std::f32::consts::TAU),
+                ..PhysicalState::default()
+            };
+            states.push(previous.clone());
+        }
+
+        Ok(states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{experiment::Experiment, protocol::Control};
+
+    const SPEC: &str = r#"
+        seed = 1
+
+        [setup]
+        outbound_steps = 300
+        inbound_steps = 0
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = false
+        time_step = { dt = 0.5 }
+
+        [model]
+        variant = "reference"
+    "#;
+
+    #[test]
+    fn replays_resampled_flight() {
+        let mut experiment = Experiment::from_toml(SPEC).unwrap();
+        let recorded = experiment.run().physical_states;
+
+        let dt = experiment.setup.time_step.dt();
+        let mut csv = String::from("t,x,y\n0,0,0\n");
+        for (i, state) in recorded.iter().enumerate() {
+            let t = (i + 1) as f32 * dt;
+            csv += &format!("{},{},{}\n", t, state.position.x, state.position.y);
+        }
+        let states = Track::read_csv(csv.as_bytes())
+            .unwrap()
+            .resample(dt, 1.0)
+            .unwrap();
+        assert_eq!(states.len(), recorded.len());
+
+        let replay = Control::Replay { states };
+        experiment.protocol = Some(experiment.setup.protocol_with_outbound(replay));
+        let replayed = experiment.run().physical_states;
+        assert_eq!(replayed.len(), recorded.len());
+        for (replayed, recorded) in replayed.iter().zip(&recorded) {
+            assert!((replayed.position - recorded.position).magnitude() < 1e-3);
+            assert!((replayed.velocity - recorded.velocity).magnitude() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn ignores_unused_columns() {
+        let csv = "# ant 7\nt,x,y,label\n0.0,0.0,0.0,nest\n1.0,3.0,4.0,feeder\n";
+        let track = Track::read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(track.times, [0.0, 1.0]);
+        assert_eq!(track.positions[1], Vector2::new(3.0, 4.0));
+
+        // Heading east, then north
+        let csv = "t,heading,note,speed\n0,1.5707964,out,2\n1,0,back,1\n3,0,,1\n";
+        let track = Track::read_csv(csv.as_bytes()).unwrap();
+        assert!((track.positions[2] - Vector2::new(2.0, 2.0)).magnitude() < 1e-5);
+
+        let csv = "t,x,y,label\n0,0,0,nest\n1,3,four,feeder\n";
+        match Track::read_csv(csv.as_bytes()) {
+            Err(TrackError::Parse { line, message }) => {
+                assert_eq!(line, 3);
+                assert!(message.starts_with("y:"));
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+}
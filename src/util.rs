@@ -1,8 +1,8 @@
-use std::cell::{RefCell, RefMut};
-
 use nalgebra::SMatrix;
-use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rand::{Rng, SeedableRng};
 use rand_distr::{Distribution, Normal};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
 
 use super::model::network::{ActivityVector, WeightMatrix};
 
@@ -26,69 +26,144 @@ pub mod activation {
     }
 }
 
+/// Seeds for the independent random streams of a [`Random`].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct StreamSeeds {
+    pub weights: u64,
+    pub activity: u64,
+    pub route: u64,
+    pub senses: u64,
+    pub wind: u64,
+    pub vision: u64,
+}
+
+impl StreamSeeds {
+    pub fn derive(seed: u64) -> StreamSeeds {
+        StreamSeeds {
+            weights: derive_seed(seed, &[0]),
+            activity: derive_seed(seed, &[1]),
+            route: derive_seed(seed, &[2]),
+            senses: derive_seed(seed, &[3]),
+            wind: derive_seed(seed, &[4]),
+            vision: derive_seed(seed, &[5]),
+        }
+    }
+}
+
+/// Random state with separate streams for weight noise, activity noise, route generation
+/// and sensing, so that drawing from one stream never changes what the others produce.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Random {
-    rng: RefCell<SmallRng>,
+    weights: Xoshiro256PlusPlus,
+    activity: Xoshiro256PlusPlus,
+    route: Xoshiro256PlusPlus,
+    senses: Xoshiro256PlusPlus,
+    wind: u64,
+    vision: u64,
     activity_noise: Normal<f32>,
     weight_noise: Normal<f32>,
 }
 
 impl Random {
     pub fn new(activity_noise: f32, weight_noise: f32, seed: Option<u64>) -> Random {
-        let rng = if let Some(seed) = seed {
-            SmallRng::seed_from_u64(seed)
-        } else {
-            SmallRng::from_entropy()
-        };
+        let seed = seed.unwrap_or_else(rand::random);
+        Self::from_seeds(activity_noise, weight_noise, StreamSeeds::derive(seed))
+    }
 
+    pub fn from_seeds(activity_noise: f32, weight_noise: f32, seeds: StreamSeeds) -> Random {
         Random {
-            rng: RefCell::new(rng),
+            weights: Xoshiro256PlusPlus::seed_from_u64(seeds.weights),
+            activity: Xoshiro256PlusPlus::seed_from_u64(seeds.activity),
+            route: Xoshiro256PlusPlus::seed_from_u64(seeds.route),
+            senses: Xoshiro256PlusPlus::seed_from_u64(seeds.senses),
+            wind: seeds.wind,
+            vision: seeds.vision,
             activity_noise: Normal::new(0.0, activity_noise).unwrap(),
             weight_noise: Normal::new(0.0, weight_noise).unwrap(),
         }
     }
 
-    pub fn rng(&self) -> RefMut<impl Rng> {
-        self.rng.borrow_mut()
+    /// Restarts the activity and sensing streams from `seed`, leaving the weights and routes
+    /// as they are, so that forks of one trial go their own ways from there on.
+    pub fn reseed(&mut self, seed: u64) {
+        let seeds = StreamSeeds::derive(seed);
+        self.activity = Xoshiro256PlusPlus::seed_from_u64(seeds.activity);
+        self.senses = Xoshiro256PlusPlus::seed_from_u64(seeds.senses);
+    }
+
+    /// Stream used for generating routes.
+    pub fn route_rng(&mut self) -> &mut impl Rng {
+        &mut self.route
+    }
+
+    /// Stream used for noise in the senses, e.g. the sky compass.
+    pub fn senses_rng(&mut self) -> &mut impl Rng {
+        &mut self.senses
+    }
+
+    /// Seed of the gusts in this trial, for winds that do not fix their own.
+    pub fn wind_seed(&self) -> u64 {
+        self.wind
+    }
+
+    /// Seed of the initial visual memory in this trial, for memories that do not fix their own.
+    pub fn vision_seed(&self) -> u64 {
+        self.vision
     }
 
     fn noisify<const N: usize, const M: usize>(
-        &self,
+        rng: &mut Xoshiro256PlusPlus,
         dist: impl Distribution<f32>,
         matrix: &SMatrix<f32, { N }, { M }>,
     ) -> SMatrix<f32, { N }, { M }> {
-        let mut rng = self.rng.borrow_mut();
-        matrix.map(|x| (x + dist.sample(&mut *rng)).clamp(0.0, 1.0))
+        matrix.map(|x| (x + dist.sample(rng)).clamp(0.0, 1.0))
     }
 
     pub fn noisify_weights<const N: usize, const M: usize>(
-        &self,
+        &mut self,
         weights: &WeightMatrix<{ N }, { M }>,
     ) -> WeightMatrix<{ N }, { M }> {
-        self.noisify(&self.weight_noise, weights)
+        Self::noisify(&mut self.weights, self.weight_noise, weights)
     }
 
     pub fn noisify_activity<const N: usize>(
-        &self,
+        &mut self,
         activity: &ActivityVector<{ N }>,
     ) -> ActivityVector<{ N }> {
-        self.noisify(&self.activity_noise, activity)
+        Self::noisify(&mut self.activity, self.activity_noise, activity)
     }
 
     pub fn noisy_sigmoid<const N: usize>(
-        &self,
+        &mut self,
         inputs: &ActivityVector<{ N }>,
         slope: f32,
         bias: f32,
     ) -> ActivityVector<{ N }> {
-        self.noisify_activity(&activation::sigmoid(&inputs, slope, bias))
+        self.noisify_activity(&activation::sigmoid(inputs, slope, bias))
     }
 
     pub fn noisy_linear<const N: usize>(
-        &self,
+        &mut self,
         inputs: &ActivityVector<{ N }>,
         slope: f32,
         bias: f32,
     ) -> ActivityVector<{ N }> {
-        self.noisify_activity(&activation::linear(&inputs, slope, bias))
+        self.noisify_activity(&activation::linear(inputs, slope, bias))
     }
 }
+
+/// SplitMix64 finalizer, used to scramble seeds so that nearby inputs give unrelated outputs.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Derives an independent child seed from a parent seed and a path of indices,
+/// e.g. `derive_seed(master, &[point, trial])`.
+pub fn derive_seed(seed: u64, path: &[u64]) -> u64 {
+    path.iter().fold(splitmix64(seed), |seed, &index| {
+        splitmix64(seed ^ splitmix64(index))
+    })
+}
@@ -0,0 +1,355 @@
+//! Visual homing: panoramic views of landmarks, and a memory of how familiar a view is.
+//!
+//! Views are learned in phases with `learn_views` set, by default the outbound route,
+//! and during homing the agent turns towards the most familiar direction it can see,
+//! in a mix with the CX's steering. As in a spec:
+//!
+//! ```toml
+//! [setup.vision]
+//! memory = { type = "infomax" }
+//! learning = "facing_nest"
+//! weight = 0.5
+//!
+//! [[setup.world.landmarks]]
+//! position = [100.0, 50.0]
+//! radius = 5.0
+//! height = 20.0
+//! ```
+//!
+//! The memory is the agent's own, so it lives with the CX and carries over into forked trials.
+
+use nalgebra::{DMatrix, DVector, Vector2};
+use rand::SeedableRng;
+use rand_distr::{Distribution, Normal};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
+
+use crate::{decode::wrap_angle, movement::PhysicalState, world::World};
+
+/// Skyline elevation in each direction, from the heading clockwise round the panorama.
+pub type View = DVector<f32>;
+
+/// An upright cylinder that stands out against the sky. Landmarks are only seen;
+/// an obstacle in the same place makes one solid.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Landmark {
+    pub position: Vector2<f32>,
+    pub radius: f32,
+    pub height: f32,
+}
+
+/// Renders the skyline seen from a position when facing `heading`.
+///
+/// Each of the `resolution` pixels holds the elevation of the highest landmark in its direction,
+/// weighted by how much of the pixel the landmark covers.
+pub fn render(world: &World, position: &Vector2<f32>, heading: f32, resolution: usize) -> View {
+    let width = std::f32::consts::TAU / resolution as f32;
+    let mut view = View::zeros(resolution);
+    for landmark in &world.landmarks {
+        let offset = landmark.position - position;
+        let distance = offset.magnitude();
+        if distance <= landmark.radius {
+            view.fill(std::f32::consts::FRAC_PI_2);
+            break;
+        }
+
+        let bearing = offset.x.atan2(offset.y);
+        let half_width = (landmark.radius / distance).asin();
+        let elevation = landmark.height.atan2(distance);
+        for (pixel, value) in view.iter_mut().enumerate() {
+            let azimuth = heading + pixel as f32 * width;
+            let centre = wrap_angle(bearing - azimuth);
+            let covered = ((centre + half_width).min(width / 2.0)
+                - (centre - half_width).max(-width / 2.0))
+            .max(0.0);
+            *value = value.max(elevation * covered / width);
+        }
+    }
+    view
+}
+
+/// The view after turning clockwise by `pixels`.
+fn rotate(view: &View, pixels: isize) -> View {
+    let n = view.len() as isize;
+    View::from_fn(view.len(), |i, _| {
+        view[(i as isize + pixels).rem_euclid(n) as usize]
+    })
+}
+
+/// Which familiarity memory to use.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MemoryKind {
+    /// Stores every view, as a baseline.
+    #[default]
+    PerfectMemory,
+    /// A single-layer infomax network, as in Baddeley et al. (2012).
+    Infomax {
+        #[serde(default = "default_learning_rate")]
+        learning_rate: f32,
+        /// Seed for the initial weights; each trial draws its own without one.
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+}
+
+fn default_learning_rate() -> f32 {
+    0.1
+}
+
+/// Learned views.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VisualMemory {
+    PerfectMemory {
+        views: Vec<View>,
+    },
+    Infomax {
+        learning_rate: f32,
+        weights: DMatrix<f32>,
+    },
+}
+
+impl VisualMemory {
+    /// An empty memory, with `trial_seed` giving the initial weights of an unseeded one.
+    pub fn new(kind: &MemoryKind, resolution: usize, trial_seed: u64) -> Self {
+        match *kind {
+            MemoryKind::PerfectMemory => VisualMemory::PerfectMemory { views: Vec::new() },
+            MemoryKind::Infomax {
+                learning_rate,
+                seed,
+            } => {
+                let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed.unwrap_or(trial_seed));
+                let normal = Normal::new(0.0, 1.0 / (resolution as f32).sqrt()).unwrap();
+                VisualMemory::Infomax {
+                    learning_rate,
+                    weights: DMatrix::from_fn(resolution, resolution, |_, _| {
+                        normal.sample(&mut rng)
+                    }),
+                }
+            }
+        }
+    }
+
+    pub fn learn(&mut self, view: &View) {
+        match self {
+            VisualMemory::PerfectMemory { views } => views.push(view.clone()),
+            VisualMemory::Infomax {
+                learning_rate,
+                weights,
+            } => {
+                let input = standardize(view);
+                let h = &*weights * input;
+                let y = h.map(f32::tanh);
+                let n = weights.ncols() as f32;
+                let update =
+                    (&*weights - (y + &h) * (h.transpose() * &*weights)) * (*learning_rate / n);
+                *weights += update;
+            }
+        }
+    }
+
+    /// How unlike the learned views a view is; lower is more familiar.
+    pub fn unfamiliarity(&self, view: &View) -> f32 {
+        match self {
+            VisualMemory::PerfectMemory { views } => views
+                .iter()
+                .map(|learned| (learned - view).norm_squared() / view.len() as f32)
+                .fold(f32::INFINITY, f32::min),
+            VisualMemory::Infomax { weights, .. } => {
+                (weights * standardize(view)).abs().sum() / weights.nrows() as f32
+            }
+        }
+    }
+}
+
+/// Zero mean and unit variance, as the infomax network expects its input.
+fn standardize(view: &View) -> View {
+    let mean = view.mean();
+    let centred = view.add_scalar(-mean);
+    let std_dev = (centred.norm_squared() / view.len() as f32).sqrt();
+    if std_dev > 0.0 {
+        centred / std_dev
+    } else {
+        centred
+    }
+}
+
+/// Which way the agent faces when it learns a view.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Learning {
+    /// Along its heading, as when learning a route to follow back.
+    #[default]
+    Travel,
+    /// Towards the nest, as ants do when they turn back and look.
+    FacingNest,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Vision {
+    #[serde(default = "default_resolution")]
+    pub resolution: usize,
+    #[serde(default)]
+    pub memory: MemoryKind,
+    #[serde(default)]
+    pub learning: Learning,
+    /// Learn a view every this many steps.
+    #[serde(default = "default_every")]
+    pub every: usize,
+    /// Largest turn either way that is scanned for the most familiar direction.
+    #[serde(default = "default_scan")]
+    pub scan: f32,
+    /// Turning rate per radian towards the most familiar direction.
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+    /// Share of the visual steering in the motor output, the rest coming from the CX.
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_resolution() -> usize {
+    72
+}
+
+fn default_every() -> usize {
+    1
+}
+
+fn default_scan() -> f32 {
+    std::f32::consts::FRAC_PI_2
+}
+
+fn default_gain() -> f32 {
+    0.5
+}
+
+fn default_weight() -> f32 {
+    0.5
+}
+
+impl Vision {
+    pub fn memory(&self, trial_seed: u64) -> VisualMemory {
+        VisualMemory::new(&self.memory, self.resolution, trial_seed)
+    }
+
+    /// Learns the view at `step` of a learning phase, if it is one of the steps to learn at.
+    pub fn learn(
+        &self,
+        memory: &mut VisualMemory,
+        world: &World,
+        state: &PhysicalState,
+        step: usize,
+    ) {
+        if !step.is_multiple_of(self.every.max(1)) {
+            return;
+        }
+        let heading = match self.learning {
+            Learning::Travel => state.heading,
+            Learning::FacingNest => (-state.position.x).atan2(-state.position.y),
+        };
+        memory.learn(&render(world, &state.position, heading, self.resolution));
+    }
+
+    /// Turn towards the most familiar direction within the scan, preferring smaller turns.
+    pub fn most_familiar(
+        &self,
+        memory: &VisualMemory,
+        world: &World,
+        state: &PhysicalState,
+    ) -> f32 {
+        let view = render(world, &state.position, state.heading, self.resolution);
+        let width = std::f32::consts::TAU / self.resolution as f32;
+        let reach = (self.scan / width).floor() as isize;
+        let mut best = (0, f32::INFINITY);
+        for pixels in (0..=reach).flat_map(|k| [k, -k]) {
+            let unfamiliarity = memory.unfamiliarity(&rotate(&view, pixels));
+            if unfamiliarity < best.1 {
+                best = (pixels, unfamiliarity);
+            }
+        }
+        best.0 as f32 * width
+    }
+
+    /// Mixes the CX's motor output with visual steering.
+    pub fn steer(
+        &self,
+        memory: &VisualMemory,
+        world: &World,
+        state: &PhysicalState,
+        motor: f32,
+    ) -> f32 {
+        let visual = self.gain * self.most_familiar(memory, world, state);
+        (1.0 - self.weight) * motor + self.weight * visual
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn initial_weights(seed: Option<u64>, trial_seed: u64) -> DMatrix<f32> {
+        let kind = MemoryKind::Infomax {
+            learning_rate: 0.1,
+            seed,
+        };
+        match VisualMemory::new(&kind, 8, trial_seed) {
+            VisualMemory::Infomax { weights, .. } => weights,
+            VisualMemory::PerfectMemory { .. } => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn trials_have_their_own_infomax_weights() {
+        assert_ne!(initial_weights(None, 1), initial_weights(None, 2));
+        assert_eq!(initial_weights(None, 1), initial_weights(None, 1));
+        assert_eq!(initial_weights(Some(7), 1), initial_weights(Some(7), 2));
+    }
+
+    fn world() -> World {
+        let landmark = |x: f32, y: f32, radius: f32, height: f32| Landmark {
+            position: Vector2::new(x, y),
+            radius,
+            height,
+        };
+        World {
+            landmarks: vec![
+                landmark(60.0, 20.0, 5.0, 20.0),
+                landmark(-30.0, 50.0, 10.0, 10.0),
+                landmark(-10.0, -70.0, 3.0, 30.0),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn turns_back_towards_a_learned_view() {
+        let world = world();
+        let learned = PhysicalState {
+            position: Vector2::new(5.0, 10.0),
+            heading: 1.0,
+            ..Default::default()
+        };
+        let turned = PhysicalState {
+            heading: 1.5,
+            ..learned.clone()
+        };
+
+        for memory in [r#"{ type = "perfect_memory" }"#, r#"{ type = "infomax" }"#] {
+            let vision: Vision = toml::from_str(&format!("memory = {memory}")).unwrap();
+            let mut visual_memory = vision.memory(1);
+            for step in 0..20 {
+                vision.learn(&mut visual_memory, &world, &learned, step);
+            }
+
+            let pixel = std::f32::consts::TAU / vision.resolution as f32;
+            let turn = vision.most_familiar(&visual_memory, &world, &turned);
+            assert!((turn + 0.5).abs() <= pixel / 2.0);
+            assert_eq!(vision.most_familiar(&visual_memory, &world, &learned), 0.0);
+
+            // Half the steering comes from the CX
+            let steering = vision.steer(&visual_memory, &world, &turned, 0.2);
+            assert!((steering - (0.5 * 0.2 + 0.5 * vision.gain * turn)).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Obstacles and walls the agent cannot pass through.
+//!
+//! In a spec, the world is part of the setup:
+//!
+//! ```toml
+//! [setup.world]
+//! collision = "slide"
+//! arena = { type = "rectangle", min = [-500.0, -500.0], max = [500.0, 500.0] }
+//!
+//! [[setup.world.obstacles]]
+//! type = "polygon"
+//! points = [[-50.0, 100.0], [50.0, 100.0], [50.0, 120.0], [-50.0, 120.0]]
+//! ```
+//!
+//! Obstacles are made of straight edges which block movement from either side,
+//! so an arena is simply an obstacle around the nest.
+
+use nalgebra::Vector2;
+use serde::{Deserialize, Serialize};
+
+use crate::{movement::PhysicalState, vision::Landmark};
+
+/// Straight edge between two end points.
+pub type Edge = (Vector2<f32>, Vector2<f32>);
+
+/// Edges approximating a circle.
+const CIRCLE_EDGES: usize = 64;
+
+/// How far the agent is kept from an edge it ran into, so it does not end up on the edge.
+const SKIN: f32 = 1e-3;
+
+/// Most edges the agent can slide along in one movement, e.g. into a corner.
+const MAX_CONTACTS: usize = 4;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Obstacle {
+    /// Closed polygon.
+    Polygon {
+        points: Vec<Vector2<f32>>,
+    },
+    /// Open chain of walls through the points.
+    Wall {
+        points: Vec<Vector2<f32>>,
+    },
+    Rectangle {
+        min: Vector2<f32>,
+        max: Vector2<f32>,
+    },
+    Circle {
+        center: Vector2<f32>,
+        radius: f32,
+    },
+}
+
+impl Obstacle {
+    /// Straight edges of the obstacle.
+    pub fn edges(&self) -> Vec<Edge> {
+        match *self {
+            Obstacle::Polygon { ref points } => closed(points),
+            Obstacle::Wall { ref points } => points.windows(2).map(|w| (w[0], w[1])).collect(),
+            Obstacle::Rectangle { min, max } => closed(&[
+                min,
+                Vector2::new(max.x, min.y),
+                max,
+                Vector2::new(min.x, max.y),
+            ]),
+            Obstacle::Circle { center, radius } => {
+                let points: Vec<Vector2<f32>> = (0..CIRCLE_EDGES)
+                    .map(|i| {
+                        let angle = std::f32::consts::TAU * i as f32 / CIRCLE_EDGES as f32;
+                        center + Vector2::new(angle.sin(), angle.cos()) * radius
+                    })
+                    .collect();
+                closed(&points)
+            }
+        }
+    }
+}
+
+fn closed(points: &[Vector2<f32>]) -> Vec<Edge> {
+    (0..points.len())
+        .map(|i| (points[i], points[(i + 1) % points.len()]))
+        .filter(|(a, b)| a != b)
+        .collect()
+}
+
+/// What happens to a movement that runs into an edge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Collision {
+    /// Keeps the part of the movement along the edge.
+    #[default]
+    Slide,
+    /// Comes to rest at the edge.
+    Stop,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct World {
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    /// Boundary the agent stays within.
+    #[serde(default)]
+    pub arena: Option<Obstacle>,
+    #[serde(default)]
+    pub collision: Collision,
+    /// Landmarks for visual homing.
+    #[serde(default)]
+    pub landmarks: Vec<Landmark>,
+}
+
+impl World {
+    pub fn is_empty(&self) -> bool {
+        self.obstacles.is_empty() && self.arena.is_none()
+    }
+
+    pub fn edges(&self) -> Vec<Edge> {
+        self.obstacles
+            .iter()
+            .chain(&self.arena)
+            .flat_map(Obstacle::edges)
+            .collect()
+    }
+
+    /// Resolves the movement from `from` to `to` against the edges in the way.
+    ///
+    /// Sliding keeps the components of the velocity and wind along the last edge hit,
+    /// stopping takes them away, so that the ground velocity stays that of the actual movement.
+    pub fn collide(&self, from: &PhysicalState, mut to: PhysicalState) -> PhysicalState {
+        if self.is_empty() {
+            return to;
+        }
+        let edges = self.edges();
+
+        let mut position = from.position;
+        let mut movement = to.position - from.position;
+        for _ in 0..MAX_CONTACTS {
+            let Some((t, (a, b))) = first_hit(&edges, &position, &movement) else {
+                to.position = position + movement;
+                return to;
+            };
+
+            let tangent = (b - a).normalize();
+            let mut normal = Vector2::new(-tangent.y, tangent.x);
+            if normal.dot(&(position - a)) < 0.0 {
+                normal = -normal;
+            }
+            position += movement * t + normal * SKIN;
+
+            match self.collision {
+                Collision::Stop => break,
+                Collision::Slide => {
+                    movement = tangent * tangent.dot(&(movement * (1.0 - t)));
+                    to.velocity = tangent * tangent.dot(&to.velocity);
+                    to.wind = tangent * tangent.dot(&to.wind);
+                }
+            }
+        }
+
+        // Stopped, or stuck in a corner
+        to.position = position;
+        to.velocity = Vector2::zeros();
+        to.wind = Vector2::zeros();
+        to
+    }
+}
+
+/// Fraction of the movement before the first edge it crosses, and that edge.
+fn first_hit(edges: &[Edge], from: &Vector2<f32>, movement: &Vector2<f32>) -> Option<(f32, Edge)> {
+    let cross = |u: &Vector2<f32>, v: &Vector2<f32>| u.x * v.y - u.y * v.x;
+    edges
+        .iter()
+        .filter_map(|&(a, b)| {
+            let edge = b - a;
+            let denominator = cross(movement, &edge);
+            if denominator.abs() < f32::EPSILON {
+                return None;
+            }
+            let offset = a - from;
+            let t = cross(&offset, &edge) / denominator;
+            let u = cross(&offset, movement) / denominator;
+            ((0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u)).then_some((t, (a, b)))
+        })
+        .min_by(|(t1, _), (t2, _)| t1.total_cmp(t2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::experiment::Experiment;
+
+    /// Outbound and homing in a round arena, with a block in the way and a wall by the nest.
+    const SPEC: &str = r#"
+        seed = 12
+
+        [setup]
+        outbound_steps = 1500
+        inbound_steps = 1500
+        acceleration_out = 0.15
+        acceleration_in = 0.1
+        vary_speed = true
+        record_memory = false
+
+        [setup.world]
+        arena = { type = "circle", center = [0.0, 0.0], radius = 150.0 }
+
+        [[setup.world.obstacles]]
+        type = "rectangle"
+        min = [-40.0, 40.0]
+        max = [40.0, 60.0]
+
+        [[setup.world.obstacles]]
+        type = "wall"
+        points = [[-30.0, -20.0], [0.0, -30.0], [30.0, -20.0]]
+
+        [model]
+        variant = "reference"
+    "#;
+
+    fn crossings(spec: &str) -> (usize, usize) {
+        let experiment = Experiment::from_toml(spec).unwrap();
+        let edges = experiment.setup.world.edges();
+        let flight = experiment.run();
+        let positions: Vec<_> = std::iter::once(Vector2::zeros())
+            .chain(flight.physical_states.iter().map(|state| state.position))
+            .collect();
+        let crossed = positions
+            .windows(2)
+            .filter(|pair| first_hit(&edges, &pair[0], &(pair[1] - pair[0])).is_some())
+            .count();
+        let outside = positions.iter().filter(|p| p.magnitude() > 150.0).count();
+        (crossed, outside)
+    }
+
+    #[test]
+    fn obstacles_are_never_crossed() {
+        assert_eq!(crossings(SPEC), (0, 0));
+        let stopping = SPEC.replace("[setup.world]", "[setup.world]\ncollision = \"stop\"");
+        assert_eq!(crossings(&stopping), (0, 0));
+    }
+
+    #[test]
+    fn sliding_keeps_the_movement_along_the_edge() {
+        let mut world = World {
+            obstacles: vec![Obstacle::Wall {
+                points: vec![Vector2::new(-10.0, 1.0), Vector2::new(10.0, 1.0)],
+            }],
+            ..Default::default()
+        };
+        let from = PhysicalState::default();
+        let to = PhysicalState {
+            position: Vector2::new(2.0, 2.0),
+            velocity: Vector2::new(2.0, 2.0),
+            ..Default::default()
+        };
+
+        let slid = world.collide(&from, to.clone());
+        assert!((slid.position - Vector2::new(2.0, 1.0 - SKIN)).magnitude() < 1e-5);
+        assert_eq!(slid.velocity, Vector2::new(2.0, 0.0));
+
+        world.collision = Collision::Stop;
+        let stopped = world.collide(&from, to);
+        assert!((stopped.position - Vector2::new(1.0, 1.0 - SKIN)).magnitude() < 1e-5);
+        assert_eq!(stopped.velocity, Vector2::zeros());
+    }
+}